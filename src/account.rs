@@ -1,11 +1,14 @@
 use rust_decimal::Decimal;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::DisputeState;
 
 //
 // AccountStatus - everything but the total, we'll calculate that
-// during serialization for output.
+// during serialization for output. The ledger only changes available and
+// held by posting to its journal, see journal.rs.
 //
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AccountStatus {
     pub client: u16,
     #[serde(with = "rust_decimal::serde::str")]
@@ -27,6 +30,30 @@ pub struct AccountStatusTotal {
     pub locked: bool,
 }
 
+//
+// StatementLine - one accepted transaction on a client's statement, with
+// the client's balances after it. `amount` is what the transaction moved
+// and `dispute_state` the state of the deposit/withdrawal it's for right
+// after this line (not where it ended up), either is empty where it doesn't
+// apply.
+//
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatementLine {
+    pub seq_num: Option<u32>, // empty for an opening balance
+    #[serde(rename = "type")]
+    pub tx_type: String,
+    pub tx: Option<u32>,
+    #[serde(with = "rust_decimal::serde::str_option")]
+    pub amount: Option<rust_decimal::Decimal>,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub available: rust_decimal::Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub held: rust_decimal::Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub total: rust_decimal::Decimal,
+    pub dispute_state: Option<DisputeState>,
+}
+
 impl AccountStatus {
     pub fn new(id : u16) -> Self {
         AccountStatus {
@@ -1,11 +1,15 @@
-use clap::Parser;
-use std::path::PathBuf;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+
+use crate::ledger::{DisputePolicy, OutputFormat, SortBy};
+use crate::transaction::InputFormat;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    #[arg(help = "File to process")]
-    pub name: PathBuf,
+    #[arg(help = "Files, directories or glob patterns to process in order, - for stdin")]
+    #[clap(required = true)]
+    pub names: Vec<String>,
     #[arg(help = "Turn on debug logging")]
     #[clap(long, short = 'd')]
     pub debug: bool,
@@ -19,4 +23,103 @@ pub struct Args {
     #[arg(help = "File to dump the internal ledger, all data")]
     #[clap(long)]
     pub statelog: Option<String>,
+    #[arg(help = "Operator file of freeze/unlock transactions, run before the input")]
+    #[clap(long)]
+    pub admin: Option<String>,
+    #[arg(help = "Dispute amounts must be the whole original, resolve/chargeback amounts all that's disputed")]
+    #[clap(long)]
+    pub strict_amounts: bool,
+    #[arg(help = "How disputed withdrawals are handled")]
+    #[clap(long, value_enum, default_value_t = DisputePolicy::DepositsOnly, hide_possible_values = true)]
+    pub dispute_policy: DisputePolicy,
+    #[arg(help = "Reject transactions timestamped before their client's last accepted one")]
+    #[clap(long)]
+    pub reject_out_of_order: bool,
+    #[arg(help = "How long after a deposit/withdrawal it can be disputed, e.g. 90d, 12h, 30m or seconds")]
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub dispute_window: Option<i64>,
+    #[arg(help = "How many seq_nums after a deposit/withdrawal it can be disputed, used without timestamps")]
+    #[clap(long, value_name = "SEQ_NUMS")]
+    pub dispute_window_seq: Option<u32>,
+    #[arg(help = "How long a dispute can stay open before it's resolved, e.g. 30d")]
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub dispute_deadline: Option<i64>,
+    #[arg(help = "How many seq_nums a dispute can stay open before it's resolved, used without timestamps")]
+    #[clap(long, value_name = "SEQ_NUMS")]
+    pub dispute_deadline_seq: Option<u32>,
+    #[arg(help = "Ledger dump (from --statelog) to resume processing from")]
+    #[clap(long)]
+    pub resume_from: Option<String>,
+    #[arg(help = "Write-ahead log, replayed on startup then appended to")]
+    #[clap(long)]
+    pub wal: Option<String>,
+    #[arg(help = "Worker threads, clients are sharded across them")]
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..), conflicts_with = "wal")]
+    pub threads: u16,
+    #[arg(help = "Format of the input files, csv or jsonl (the --admin file is always csv)")]
+    #[clap(long, value_enum, default_value_t = InputFormat::Csv, hide_possible_values = true)]
+    pub input_format: InputFormat,
+    #[arg(help = "Format of the client balances, csv, json or jsonl")]
+    #[clap(long, value_enum, default_value_t = OutputFormat::Csv, hide_possible_values = true)]
+    pub output_format: OutputFormat,
+    #[arg(help = "Order of the client balances, client, total, available or held")]
+    #[clap(long, value_enum, default_value_t = SortBy::Client, hide_possible_values = true)]
+    pub sort_by: SortBy,
+    #[arg(help = "CSV file to write rejected rows to, with the reason")]
+    #[clap(long)]
+    pub rejects: Option<String>,
+    #[arg(help = "File to write the run summary (JSON) to, otherwise it goes to stderr")]
+    #[clap(long)]
+    pub summary: Option<String>,
+    #[arg(help = "Write this client's statement (in --output-format) instead of the balances")]
+    #[clap(long, value_name = "CLIENT")]
+    pub statement: Option<u16>,
+    #[arg(help = "Write the balances as they were after this seq_num instead of the current ones")]
+    #[clap(long, value_name = "SEQ_NUM", conflicts_with = "statement")]
+    pub as_of: Option<u32>,
+}
+
+impl Args {
+    ///
+    /// Check the options clap can't check by itself
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), clap::Error>`: `--rejects` needs a single thread, the
+    ///   shards reject transactions on their own threads
+    ///
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        if self.rejects.is_some() && self.threads > 1 {
+            return Err(Args::command().error(
+                ErrorKind::ArgumentConflict,
+                "--rejects can't be used with more than one --threads",
+            ));
+        }
+        Ok(())
+    }
+}
+
+//
+// parse_duration - a number of seconds, or a number followed by s, m, h or d.
+//
+fn parse_duration(value: &str) -> Result<i64, String> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&value[..index], unit),
+        _ => (value, 's'),
+    };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit '{}', expected s, m, h or d", unit)),
+    };
+    number
+        .parse::<u32>()
+        .map(|number| i64::from(number) * multiplier)
+        .map_err(|e| format!("'{}': {}", value, e))
 }
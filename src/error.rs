@@ -0,0 +1,372 @@
+use chrono::{DateTime, Utc};
+use core::fmt;
+use rust_decimal::Decimal;
+use std::error::Error;
+use std::io;
+
+use crate::ledger::{DisputeState, LEDGER_FORMAT_VERSION};
+use crate::transaction::TransactionType;
+
+//
+// PaymentError - every way reading or processing a transaction can fail.
+//
+// Callers can match on the variant to decide what to do with a failure
+// rather than picking apart the message.
+//
+#[derive(Debug)]
+pub enum PaymentError {
+    /// A row couldn't be turned into a `Transaction`. `line` is the line in
+    /// the input the row starts on and `field` the column that was bad.
+    ParseError {
+        line: u32,
+        field: String,
+        message: String,
+    },
+    /// A deposit/withdrawal reused a tx_id that is already in the ledger.
+    DuplicateTransaction(u32),
+    /// A dispute/resolve/chargeback referred to a tx_id we don't have.
+    UnknownTransaction(u32),
+    /// The client isn't in the ledger.
+    UnknownClient(u16),
+    /// A deposit/withdrawal without an amount.
+    MissingAmount(u32),
+    /// A deposit/withdrawal of nothing, or of a negative amount.
+    InvalidAmount { tx_id: u32, amount: Decimal },
+    InsufficientFunds {
+        client_id: u16,
+        tx_id: u32,
+        available: Decimal,
+        requested: Decimal,
+    },
+    AccountLocked(u16),
+    /// An admin transaction arrived on the transaction feed, or a regular
+    /// transaction arrived on the operator input.
+    UnauthorizedTransaction {
+        tx_id: u32,
+        tx_type: TransactionType,
+    },
+    /// In strict mode, the amount on a dispute/resolve/chargeback didn't
+    /// match the original.
+    AmountMismatch {
+        tx_id: u32,
+        expected: Decimal,
+        found: Decimal,
+    },
+    /// A dispute/resolve/chargeback came from a client that doesn't own the
+    /// referenced transaction.
+    ClientMismatch {
+        tx_id: u32,
+        owner: u16,
+        client_id: u16,
+    },
+    /// The dispute policy doesn't allow this transaction to be disputed.
+    DisputeNotAllowed {
+        tx_id: u32,
+        tx_type: TransactionType,
+    },
+    InvalidStateTransition {
+        tx_id: u32,
+        from: DisputeState,
+        to: DisputeState,
+    },
+    /// A dispute for more than is left undisputed of the original, or for
+    /// nothing at all.
+    InvalidDisputeAmount {
+        tx_id: u32,
+        undisputed: Decimal,
+        requested: Decimal,
+    },
+    /// A dispute raised after the dispute window closed on the original, or
+    /// a chargeback for a dispute left open past the deadline.
+    DisputeWindowClosed {
+        tx_id: u32,
+        tx_type: TransactionType,
+    },
+    /// With out of order rejection on, a transaction timestamped before the
+    /// last one accepted for its client.
+    TimestampOutOfOrder {
+        client_id: u16,
+        tx_id: u32,
+        timestamp: DateTime<Utc>,
+        last: DateTime<Utc>,
+    },
+    /// A transfer without a destination client.
+    MissingDestination(u32),
+    /// A transfer from a client to themselves.
+    SelfTransfer { tx_id: u32, client_id: u16 },
+    /// A transfer of nothing, or of a negative amount.
+    InvalidTransferAmount { tx_id: u32, amount: Decimal },
+    /// A transfer (or a dispute of one) between clients on different
+    /// shards, which one shard can't do without the destination being lent
+    /// to it.
+    CrossShardTransfer {
+        tx_id: u32,
+        client_id: u16,
+        destination: u16,
+    },
+    /// A journal entry whose debits and credits differ.
+    UnbalancedJournal {
+        client_id: u16,
+        debits: Decimal,
+        credits: Decimal,
+    },
+    /// A client's balances aren't what their journal accounts add up to.
+    JournalMismatch(u16),
+    /// A ledger dump with a format version this build can't migrate.
+    UnsupportedLedgerVersion(u32),
+    /// A ledger dump with a header that has no version.
+    MissingLedgerVersion,
+    /// Replaying a write-ahead log record was accepted when the log says it
+    /// was rejected (`accepted`), or the other way round.
+    WalOutcomeMismatch { lsn: u64, accepted: bool },
+    /// Replaying a write-ahead log record left the client with a different
+    /// account than the log has.
+    WalBalanceMismatch(u64),
+    Io(io::Error),
+    Csv(csv::Error),
+    Json(serde_json::Error),
+}
+
+impl PaymentError {
+    /// Build a `ParseError` for a single field of a row.
+    pub fn parse(line: u32, field: &str, message: impl Into<String>) -> Self {
+        PaymentError::ParseError {
+            line,
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// Attach the input line to a `ParseError` that was built without one,
+    /// any other error is returned untouched.
+    pub fn at_line(self, line: u32) -> Self {
+        match self {
+            PaymentError::ParseError { field, message, .. } => PaymentError::ParseError {
+                line,
+                field,
+                message,
+            },
+            other => other,
+        }
+    }
+
+    /// A short, stable code for the kind of error, for machine readable
+    /// output such as the rejects file.
+    pub fn reason_code(&self) -> &'static str {
+        match self {
+            PaymentError::ParseError { .. } => "parse_error",
+            PaymentError::DuplicateTransaction(_) => "duplicate_transaction",
+            PaymentError::UnknownTransaction(_) => "unknown_transaction",
+            PaymentError::UnknownClient(_) => "unknown_client",
+            PaymentError::MissingAmount(_) => "missing_amount",
+            PaymentError::InvalidAmount { .. } => "invalid_amount",
+            PaymentError::InsufficientFunds { .. } => "insufficient_funds",
+            PaymentError::AccountLocked(_) => "account_locked",
+            PaymentError::UnauthorizedTransaction { .. } => "unauthorized_transaction",
+            PaymentError::AmountMismatch { .. } => "amount_mismatch",
+            PaymentError::ClientMismatch { .. } => "client_mismatch",
+            PaymentError::DisputeNotAllowed { .. } => "dispute_not_allowed",
+            PaymentError::InvalidStateTransition { .. } => "invalid_state_transition",
+            PaymentError::InvalidDisputeAmount { .. } => "invalid_dispute_amount",
+            PaymentError::DisputeWindowClosed { .. } => "dispute_window_closed",
+            PaymentError::TimestampOutOfOrder { .. } => "timestamp_out_of_order",
+            PaymentError::MissingDestination(_) => "missing_destination",
+            PaymentError::SelfTransfer { .. } => "self_transfer",
+            PaymentError::InvalidTransferAmount { .. } => "invalid_transfer_amount",
+            PaymentError::CrossShardTransfer { .. } => "cross_shard_transfer",
+            PaymentError::UnbalancedJournal { .. } => "unbalanced_journal",
+            PaymentError::JournalMismatch(_) => "journal_mismatch",
+            PaymentError::UnsupportedLedgerVersion(_) => "unsupported_ledger_version",
+            PaymentError::MissingLedgerVersion => "missing_ledger_version",
+            PaymentError::WalOutcomeMismatch { .. } => "wal_outcome_mismatch",
+            PaymentError::WalBalanceMismatch(_) => "wal_balance_mismatch",
+            PaymentError::Io(_) => "io_error",
+            PaymentError::Csv(_) => "csv_error",
+            PaymentError::Json(_) => "json_error",
+        }
+    }
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaymentError::ParseError {
+                line,
+                field,
+                message,
+            } => write!(f, "{} (field:{} line:{})", message, field, line),
+            PaymentError::DuplicateTransaction(tx_id) => {
+                write!(f, "Transaction {} already seen", tx_id)
+            }
+            PaymentError::UnknownTransaction(tx_id) => {
+                write!(f, "Transaction {} not found in ledger", tx_id)
+            }
+            PaymentError::UnknownClient(client_id) => {
+                write!(f, "Client {} not found in ledger", client_id)
+            }
+            PaymentError::MissingAmount(tx_id) => {
+                write!(f, "Transaction {} has no amount", tx_id)
+            }
+            PaymentError::InvalidAmount { tx_id, amount } => {
+                write!(f, "Transaction {} of {} isn't a positive amount", tx_id, amount)
+            }
+            PaymentError::InsufficientFunds {
+                client_id,
+                tx_id,
+                available,
+                requested,
+            } => write!(
+                f,
+                "Client {} has {} available, can't withdraw {} for transaction {}",
+                client_id, available, requested, tx_id
+            ),
+            PaymentError::AccountLocked(client_id) => {
+                write!(f, "Client {} account is locked", client_id)
+            }
+            PaymentError::UnauthorizedTransaction { tx_id, tx_type } => write!(
+                f,
+                "Transaction {} of type {:?} isn't allowed from this input",
+                tx_id, tx_type
+            ),
+            PaymentError::AmountMismatch {
+                tx_id,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Old amount in transaction: {} was: {}, not equal to disputed amount {}",
+                tx_id, expected, found
+            ),
+            PaymentError::ClientMismatch {
+                tx_id,
+                owner,
+                client_id,
+            } => write!(
+                f,
+                "Transaction {} belongs to client {}, not client {}",
+                tx_id, owner, client_id
+            ),
+            PaymentError::DisputeNotAllowed { tx_id, tx_type } => write!(
+                f,
+                "Transaction {} is a {:?} and can't be disputed",
+                tx_id, tx_type
+            ),
+            PaymentError::InvalidStateTransition { tx_id, from, to } => write!(
+                f,
+                "Transaction {} can't move from {:?} to {:?}",
+                tx_id, from, to
+            ),
+            PaymentError::InvalidDisputeAmount {
+                tx_id,
+                undisputed,
+                requested,
+            } => write!(
+                f,
+                "Can't dispute {} of transaction {}, {} is undisputed",
+                requested, tx_id, undisputed
+            ),
+            PaymentError::DisputeWindowClosed { tx_id, tx_type } => write!(
+                f,
+                "Transaction {} is too old for a {}",
+                tx_id,
+                tx_type.as_str()
+            ),
+            PaymentError::TimestampOutOfOrder {
+                client_id,
+                tx_id,
+                timestamp,
+                last,
+            } => write!(
+                f,
+                "Transaction {} at {} is before client {}'s last transaction at {}",
+                tx_id,
+                timestamp.to_rfc3339(),
+                client_id,
+                last.to_rfc3339()
+            ),
+            PaymentError::MissingDestination(tx_id) => {
+                write!(f, "Transfer {} has no destination", tx_id)
+            }
+            PaymentError::SelfTransfer { tx_id, client_id } => write!(
+                f,
+                "Transfer {} is from client {} to themselves",
+                tx_id, client_id
+            ),
+            PaymentError::InvalidTransferAmount { tx_id, amount } => {
+                write!(f, "Transfer {} of {} isn't a positive amount", tx_id, amount)
+            }
+            PaymentError::CrossShardTransfer {
+                tx_id,
+                client_id,
+                destination,
+            } => write!(
+                f,
+                "Transfer {} from client {} to client {} is between shards",
+                tx_id, client_id, destination
+            ),
+            PaymentError::UnbalancedJournal {
+                client_id,
+                debits,
+                credits,
+            } => write!(
+                f,
+                "Journal entry for client {} doesn't balance, debits {} credits {}",
+                client_id, debits, credits
+            ),
+            PaymentError::JournalMismatch(client_id) => {
+                write!(f, "Client {} balances don't match the journal", client_id)
+            }
+            PaymentError::UnsupportedLedgerVersion(version) => write!(
+                f,
+                "Unsupported ledger format version {}, expected {}",
+                version, LEDGER_FORMAT_VERSION
+            ),
+            PaymentError::MissingLedgerVersion => {
+                write!(f, "Ledger dump has no format version")
+            }
+            PaymentError::WalOutcomeMismatch { lsn, accepted } => write!(
+                f,
+                "Write-ahead log record {} was {} on replay, not as logged",
+                lsn,
+                if *accepted { "accepted" } else { "rejected" }
+            ),
+            PaymentError::WalBalanceMismatch(lsn) => write!(
+                f,
+                "Write-ahead log record {} replayed to a different balance",
+                lsn
+            ),
+            PaymentError::Io(e) => write!(f, "{}", e),
+            PaymentError::Csv(e) => write!(f, "{}", e),
+            PaymentError::Json(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PaymentError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PaymentError::Io(e) => Some(e),
+            PaymentError::Csv(e) => Some(e),
+            PaymentError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PaymentError {
+    fn from(e: io::Error) -> Self {
+        PaymentError::Io(e)
+    }
+}
+
+impl From<csv::Error> for PaymentError {
+    fn from(e: csv::Error) -> Self {
+        PaymentError::Csv(e)
+    }
+}
+
+impl From<serde_json::Error> for PaymentError {
+    fn from(e: serde_json::Error) -> Self {
+        PaymentError::Json(e)
+    }
+}
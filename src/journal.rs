@@ -0,0 +1,401 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::account::AccountStatus;
+use crate::error::PaymentError;
+use crate::ledger::DisputeState;
+use crate::transaction::{Transaction, TransactionType};
+
+//
+// Double-entry journal
+//
+// Every accepted transaction that moves money posts a balanced entry into
+// the journal, the client balances in the ledger are what the journal
+// says they are. The books are kept from the house's side:
+//
+//   ClientAvailable(client)  liability  funds the client can withdraw
+//   ClientHeld(client)       liability  funds held for a dispute
+//   Settlement               asset      money the house holds, in from
+//                                       deposits and out for withdrawals
+//                                       and charged back deposits
+//   ChargebackLoss           expense    withdrawals disputed (and then
+//                                       charged back) that the house pays
+//   Clearing                 asset      funds on their way from one client
+//                                       to another, back to zero once both
+//                                       sides of a transfer are posted
+//
+// The entries posted for each transaction:
+//
+//   deposit                     Dr Settlement       Cr ClientAvailable
+//   withdrawal                  Dr ClientAvailable  Cr Settlement
+//   dispute of a deposit        Dr ClientAvailable  Cr ClientHeld
+//   resolve of a deposit        Dr ClientHeld       Cr ClientAvailable
+//   chargeback of a deposit     Dr ClientHeld       Cr Settlement
+//   dispute of a withdrawal     Dr ChargebackLoss   Cr ClientHeld
+//   resolve of a withdrawal     Dr ClientHeld       Cr ChargebackLoss
+//   chargeback of a withdrawal  Dr ClientHeld       Cr ClientAvailable
+//
+// A transfer, and a dispute or resolve of one, posts an entry for each of
+// the two clients, through Clearing:
+//
+//   transfer             source       Dr ClientAvailable  Cr Clearing
+//                        destination  Dr Clearing         Cr ClientAvailable
+//   dispute of a         source       Dr Clearing         Cr ClientHeld
+//   transfer             destination  Dr ClientAvailable  Cr Clearing
+//   resolve of a         source       Dr ClientHeld       Cr Clearing
+//   transfer             destination  Dr Clearing         Cr ClientAvailable
+//   chargeback of a      source       Dr ClientHeld       Cr ClientAvailable
+//   transfer
+//
+// The chargeback only posts for the source, the destination already gave
+// the funds back when it was disputed.
+//
+// Freeze/unlock, and disputes of withdrawals that are ignored, post an
+// entry with no lines, so the journal has every accepted transaction.
+//
+// Entries are kept in seq_num order, which is the order a ledger accepts
+// them in, so the accounts at any seq_num can be rebuilt by applying the
+// entries up to it.
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum JournalAccount {
+    ClientAvailable(u16),
+    ClientHeld(u16),
+    Settlement,
+    ChargebackLoss,
+    Clearing,
+}
+
+impl JournalAccount {
+    /// The side an increase to this account is posted on. Client accounts
+    /// are the house's liabilities, so they go up with a credit.
+    pub fn normal_side(&self) -> Side {
+        match self {
+            JournalAccount::ClientAvailable(_) | JournalAccount::ClientHeld(_) => Side::Credit,
+            JournalAccount::Settlement
+            | JournalAccount::ChargebackLoss
+            | JournalAccount::Clearing => Side::Debit,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Side {
+    Debit,
+    Credit,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalLine {
+    pub account: JournalAccount,
+    pub side: Side,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub amount: Decimal,
+}
+
+impl JournalLine {
+    pub fn debit(account: JournalAccount, amount: Decimal) -> Self {
+        JournalLine {
+            account,
+            side: Side::Debit,
+            amount,
+        }
+    }
+
+    pub fn credit(account: JournalAccount, amount: Decimal) -> Self {
+        JournalLine {
+            account,
+            side: Side::Credit,
+            amount,
+        }
+    }
+
+    /// A line that changes an account's balance by `change`, which can be
+    /// negative.
+    pub fn for_change(account: JournalAccount, change: Decimal) -> Self {
+        let side = match (account.normal_side(), change.is_sign_negative()) {
+            (side, false) => side,
+            (Side::Debit, true) => Side::Credit,
+            (Side::Credit, true) => Side::Debit,
+        };
+        JournalLine {
+            account,
+            side,
+            amount: change.abs(),
+        }
+    }
+
+    //
+    // change - what this line does to its account's balance
+    //
+    fn change(&self) -> Decimal {
+        if self.side == self.account.normal_side() {
+            self.amount
+        } else {
+            -self.amount
+        }
+    }
+}
+
+/// The two lines of moving `amount` from one account to another.
+pub fn transfer(
+    debit: JournalAccount,
+    credit: JournalAccount,
+    amount: Decimal,
+) -> Vec<JournalLine> {
+    vec![
+        JournalLine::debit(debit, amount),
+        JournalLine::credit(credit, amount),
+    ]
+}
+
+//
+// EntrySource - why an entry was posted. Opening balances are for ledgers
+// dumped before there was a journal, see Ledger::load_ledger, and carry
+// whether the account was locked then.
+//
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum EntrySource {
+    Transaction {
+        seq_num: u32,
+        tx_id: u32,
+        tx_type: TransactionType,
+        // The state the deposit/withdrawal the transaction is for moved to
+        #[serde(default)]
+        dispute_state: Option<DisputeState>,
+    },
+    OpeningBalance {
+        locked: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub client_id: u16,
+    pub source: EntrySource,
+    pub lines: Vec<JournalLine>,
+}
+
+impl JournalEntry {
+    /// The entry for a transaction, `dispute_state` is where the deposit or
+    /// withdrawal it's for moved to (if anywhere).
+    pub fn for_transaction(
+        transaction: &Transaction,
+        lines: Vec<JournalLine>,
+        dispute_state: Option<DisputeState>,
+    ) -> Self {
+        JournalEntry {
+            client_id: transaction.client_id,
+            source: EntrySource::Transaction {
+                seq_num: transaction.seq_num,
+                tx_id: transaction.tx_id,
+                tx_type: transaction.tx_type.clone(),
+                dispute_state,
+            },
+            lines,
+        }
+    }
+
+    /// The seq_num of the transaction that posted this, none for opening
+    /// balances.
+    pub fn seq_num(&self) -> Option<u32> {
+        match self.source {
+            EntrySource::Transaction { seq_num, .. } => Some(seq_num),
+            EntrySource::OpeningBalance { .. } => None,
+        }
+    }
+
+    //
+    // apply_to - what this entry does to its client's account. Chargebacks
+    // and freezes lock it, an unlock unlocks it.
+    //
+    fn apply_to(&self, account: &mut AccountStatus) {
+        account.available += self.change(&JournalAccount::ClientAvailable(self.client_id));
+        account.held += self.change(&JournalAccount::ClientHeld(self.client_id));
+        match &self.source {
+            EntrySource::Transaction { tx_type, .. } => match tx_type {
+                TransactionType::Chargeback | TransactionType::Freeze => account.locked = true,
+                TransactionType::Unlock => account.locked = false,
+                _ => {}
+            },
+            EntrySource::OpeningBalance { locked } => account.locked = *locked,
+        }
+    }
+
+    /// Total of the debit lines and of the credit lines.
+    pub fn totals(&self) -> (Decimal, Decimal) {
+        self.lines.iter().fold(
+            (Decimal::ZERO, Decimal::ZERO),
+            |(debits, credits), line| match line.side {
+                Side::Debit => (debits + line.amount, credits),
+                Side::Credit => (debits, credits + line.amount),
+            },
+        )
+    }
+
+    /// How much this entry changes an account's balance.
+    pub fn change(&self, account: &JournalAccount) -> Decimal {
+        self.lines
+            .iter()
+            .filter(|line| line.account == *account)
+            .map(JournalLine::change)
+            .sum()
+    }
+
+    ///
+    /// Check debits equal credits
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), PaymentError>`: `UnbalancedJournal` if they don't
+    ///
+    pub fn check_balanced(&self) -> Result<(), PaymentError> {
+        let (debits, credits) = self.totals();
+        if debits != credits {
+            return Err(PaymentError::UnbalancedJournal {
+                client_id: self.client_id,
+                debits,
+                credits,
+            });
+        }
+        Ok(())
+    }
+}
+
+//
+// Journal - the entries in the order they were posted, and the balance of
+// every account they've touched. Only the entries are dumped, the balances
+// are added back up when it's loaded.
+//
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "JournalEntries")]
+pub struct Journal {
+    entries: Vec<JournalEntry>,
+    #[serde(skip)]
+    balances: HashMap<JournalAccount, Decimal>,
+}
+
+#[derive(Deserialize)]
+struct JournalEntries {
+    entries: Vec<JournalEntry>,
+}
+
+impl From<JournalEntries> for Journal {
+    fn from(dump: JournalEntries) -> Self {
+        Journal::from_entries(dump.entries)
+    }
+}
+
+impl Journal {
+    /// A journal of entries already posted, e.g. from another ledger.
+    pub fn from_entries(entries: Vec<JournalEntry>) -> Self {
+        let mut journal = Journal::default();
+        for entry in entries {
+            journal.apply(entry);
+        }
+        journal
+    }
+
+    /// Every entry, oldest first.
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Take the entries out, oldest first.
+    pub fn into_entries(self) -> Vec<JournalEntry> {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// An account's balance, zero if nothing was ever posted to it.
+    pub fn balance(&self, account: &JournalAccount) -> Decimal {
+        self.balances.get(account).copied().unwrap_or(Decimal::ZERO)
+    }
+
+    ///
+    /// The client accounts as they were at a seq_num
+    ///
+    /// Applies the entries from the start, up to and including `seq_num`.
+    /// Nothing is kept between calls, it's only asked for once a run.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `seq_num`: point in time, entries with a later seq_num are left out
+    ///
+    /// # Returns
+    ///
+    /// * `HashMap<u16, AccountStatus>`: every client with an entry by then
+    ///
+    pub fn clients_as_of(&self, seq_num: u32) -> HashMap<u16, AccountStatus> {
+        let end = self
+            .entries
+            .partition_point(|entry| entry.seq_num() <= Some(seq_num));
+        let mut clients = HashMap::new();
+        for entry in &self.entries[..end] {
+            entry.apply_to(
+                clients
+                    .entry(entry.client_id)
+                    .or_insert_with(|| AccountStatus::new(entry.client_id)),
+            );
+        }
+        clients
+    }
+
+    ///
+    /// Post an entry
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `entry`: JournalEntry to post
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), PaymentError>`: `UnbalancedJournal` if debits and
+    ///   credits differ, the journal is left untouched.
+    ///
+    pub fn post(&mut self, entry: JournalEntry) -> Result<(), PaymentError> {
+        entry.check_balanced()?;
+        self.apply(entry);
+        Ok(())
+    }
+
+    ///
+    /// Check the books balance
+    ///
+    /// Every entry has to balance, and so the debits over the whole journal
+    /// equal the credits.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Decimal, Decimal), PaymentError>`: total debits and
+    ///   credits, `UnbalancedJournal` for the first entry that doesn't.
+    ///
+    pub fn check(&self) -> Result<(Decimal, Decimal), PaymentError> {
+        let mut total = (Decimal::ZERO, Decimal::ZERO);
+        for entry in &self.entries {
+            entry.check_balanced()?;
+            let (debits, credits) = entry.totals();
+            total.0 += debits;
+            total.1 += credits;
+        }
+        Ok(total)
+    }
+
+    //
+    // apply - add an entry and its lines to the balances, it's already been
+    // checked.
+    //
+    fn apply(&mut self, entry: JournalEntry) {
+        for line in &entry.lines {
+            *self.balances.entry(line.account).or_default() += line.change();
+        }
+        self.entries.push(entry);
+    }
+}
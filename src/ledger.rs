@@ -1,25 +1,406 @@
 use std::collections::HashMap;
 
-use crate::account::{AccountStatus, AccountStatusTotal};
-use crate::transaction::{Transaction, TransactionType};
+use crate::account::{AccountStatus, AccountStatusTotal, StatementLine};
+use crate::error::PaymentError;
+use crate::journal::{transfer, EntrySource, Journal, JournalAccount, JournalEntry, JournalLine};
+use crate::summary::LedgerStats;
+use crate::transaction::{EventTime, Transaction, TransactionType};
+use crate::wal::{Wal, WalOutcome};
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::ValueEnum;
 use csv::Writer;
 use log::debug;
-use serde::Serialize;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs::File;
+use std::io::BufReader;
 
-#[derive(Debug, Serialize)]
+//
+// Version of the format written by dump_ledger. Bump it whenever a change to
+// the ledger (or anything in it) changes the dump, and add a step to
+// migrate_ledger so older dumps can still be loaded.
+//
+pub const LEDGER_FORMAT_VERSION: u32 = 3;
+
+//
+// DisputeState - where a deposit/withdrawal is in the dispute lifecycle.
+//
+//   Processed -> Disputed -> Resolved
+//                         -> ChargedBack
+//
+// ChargedBack is final. A dispute can be for part of the original, so
+// Disputed or Resolved can be disputed again while some of it is left.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DisputeState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl DisputeState {
+    /// Is moving from this state to `next` a legal step in the lifecycle?
+    pub fn can_transition_to(self, next: DisputeState) -> bool {
+        matches!(
+            (self, next),
+            (DisputeState::Processed, DisputeState::Disputed)
+                | (DisputeState::Disputed, DisputeState::Resolved)
+                | (DisputeState::Disputed, DisputeState::ChargedBack)
+        )
+    }
+}
+
+//
+// DisputePolicy - what a dispute against a withdrawal does.
+//
+// Disputes against deposits always hold the deposited funds.
+//
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+pub enum DisputePolicy {
+    /// Only deposits can be disputed, a dispute of a withdrawal is ignored
+    #[default]
+    DepositsOnly,
+    /// A disputed withdrawal is a claim to reverse it. The withdrawn amount
+    /// is held, a resolve drops it and a chargeback returns it to the client
+    WithdrawalsAsReversal,
+    /// A dispute of a withdrawal is rejected as an error
+    Reject,
+}
+
+//
+// DisputeWindow - how long after one event a follow up is still in time.
+// Measured in seconds when both events have a timestamp and `seconds` is
+// set, otherwise in seq_nums. With neither limit set it never closes.
+//
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DisputeWindow {
+    pub seconds: Option<i64>,
+    pub seq_nums: Option<u32>,
+}
+
+impl DisputeWindow {
+    /// Is `later` past the window opened by `earlier`? Right on the limit
+    /// is still in time.
+    pub fn is_closed(&self, earlier: &EventTime, later: &EventTime) -> bool {
+        match (earlier.timestamp, later.timestamp, self.seconds) {
+            (Some(earlier), Some(later), Some(seconds)) => {
+                later - earlier > TimeDelta::seconds(seconds)
+            }
+            _ => self
+                .seq_nums
+                .is_some_and(|seq_nums| later.seq_num.saturating_sub(earlier.seq_num) > seq_nums),
+        }
+    }
+
+    /// Does the window ever close?
+    pub fn is_set(&self) -> bool {
+        self.seconds.is_some() || self.seq_nums.is_some()
+    }
+}
+
+//
+// OutputFormat - how the client balances are written.
+//
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// CSV with a header row
+    #[default]
+    Csv,
+    /// A single json array
+    Json,
+    /// One json object per line
+    Jsonl,
+}
+
+//
+// SortBy - the order client balances are written in. Always ascending, with
+// ties broken by client id so the output is the same from run to run.
+//
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum SortBy {
+    #[default]
+    Client,
+    Total,
+    Available,
+    Held,
+}
+
+//
+// TransactionRecord - the original transaction as we received it, plus
+// where it currently is in the dispute lifecycle and when it was first
+// disputed. `disputed` is how much of it is under dispute (held) now,
+// `resolved` and `charged_back` how much of it went each way.
+//
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    pub transaction: Transaction,
+    pub state: DisputeState,
+    #[serde(default)]
+    pub disputed_at: Option<EventTime>,
+    pub disputed: Decimal,
+    pub resolved: Decimal,
+    pub charged_back: Decimal,
+}
+
+impl TransactionRecord {
+    /// How much of the original has never been disputed
+    pub fn undisputed(&self) -> Decimal {
+        self.transaction.amount.unwrap_or_default()
+            - self.disputed
+            - self.resolved
+            - self.charged_back
+    }
+}
+
+// Fields missing from a dump (added since it was written) get their default
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Ledger {
     pub by_client_id: HashMap<u16, AccountStatus>,
-    pub by_transaction_id: HashMap<u32, Transaction>,
+    pub by_transaction_id: HashMap<u32, TransactionRecord>,
+    // When set, an amount supplied on a dispute has to match the original
+    // transaction and on a resolve/chargeback what's under dispute. Otherwise
+    // a dispute's amount is the part disputed and the others are ignored.
+    pub strict_amounts: bool,
+    // What disputing a withdrawal does
+    pub dispute_policy: DisputePolicy,
+    // When set, a transaction timestamped before the last one accepted for
+    // its client is rejected. Untimestamped transactions are always let in.
+    pub reject_out_of_order: bool,
+    // How long after a deposit/withdrawal it can be disputed
+    pub dispute_window: DisputeWindow,
+    // How long a dispute can stay open. A chargeback after that is refused
+    // and resolve_expired_disputes resolves it.
+    pub dispute_deadline: DisputeWindow,
+    // Timestamp of each client's latest accepted transaction that had one
+    pub last_timestamps: HashMap<u16, DateTime<Utc>>,
+    // Log sequence number of the last write-ahead log record reflected in
+    // this ledger, recovery replays anything after it.
+    pub last_lsn: u64,
+    // Highest seq_num of any transaction given to this ledger, a run resuming
+    // from a dump carries on numbering after it (see ReadSession::resume).
+    pub last_seq_num: Option<u32>,
+    // Every movement of funds, the client balances are what it adds up to
+    pub journal: Journal,
+    // Counts for this run's summary, they start again on every run
+    #[serde(skip)]
+    pub stats: LedgerStats,
+    // On a shard's ledger, which shard it is and how many there are (see
+    // shard::split_ledger). Transfers to a client on another shard are
+    // refused, unless it's been lent (see borrow_client).
+    #[serde(skip)]
+    pub shard: Option<(usize, usize)>,
+    // On a shard's ledger, tx_ids that another shard has and the client they
+    // belong to, see add_foreign_transaction
+    #[serde(skip)]
+    foreign_transactions: HashMap<u32, u16>,
+    // On a shard's ledger, a client from another shard lent to it for the
+    // transaction being processed, see borrow_client
+    #[serde(skip)]
+    borrowed: Option<u16>,
+    #[serde(skip)]
+    wal: Option<Wal>,
+}
+
+//
+// What dump_ledger writes. load_ledger reads it back as a raw json value so
+// it can be migrated before it's deserialized.
+//
+#[derive(Serialize)]
+struct LedgerDumpRef<'a> {
+    version: u32,
+    ledger: &'a Ledger,
+}
+
+//
+// migrate_ledger - bring a dumped ledger from `version` up to
+// LEDGER_FORMAT_VERSION. Each older version gets an arm that upgrades it one
+// version and loops.
+//
+fn migrate_ledger(
+    mut version: u32,
+    mut ledger: serde_json::Value,
+) -> Result<serde_json::Value, PaymentError> {
+    loop {
+        match version {
+            LEDGER_FORMAT_VERSION => return Ok(ledger),
+            1 => {
+                add_transaction_records(&mut ledger);
+                add_opening_journal(&mut ledger)?;
+                add_dispute_amounts(&mut ledger);
+                version = 2;
+            }
+            2 => {
+                // Transfers only added a transaction's destination_id, which
+                // defaults, and the clearing account, which older journals
+                // never used
+                version = 3;
+            }
+            _ => return Err(PaymentError::UnsupportedLedgerVersion(version)),
+        }
+    }
+}
+
+//
+// add_transaction_records - version 1 to 2. A dump from before there was a
+// version header (or a dispute lifecycle) kept the last transaction
+// accepted for each tx_id, a dispute/resolve/chargeback replacing the
+// deposit it was for. Each becomes a record in the state that says, a
+// replaced one as the deposit it was.
+//
+fn add_transaction_records(ledger: &mut serde_json::Value) {
+    let Some(records) = ledger
+        .get_mut("by_transaction_id")
+        .and_then(|records| records.as_object_mut())
+    else {
+        return;
+    };
+    for record in records.values_mut() {
+        if record.get("transaction").is_some() {
+            continue;
+        }
+        let mut transaction = record.take();
+        let state = match transaction["tx_type"].as_str().unwrap_or_default() {
+            "Dispute" => "Disputed",
+            "Resolve" => "Resolved",
+            "Chargeback" => "ChargedBack",
+            _ => "Processed",
+        };
+        if state != "Processed" {
+            transaction["tx_type"] = "Deposit".into();
+        }
+        *record = serde_json::json!({ "transaction": transaction, "state": state });
+    }
+}
+
+//
+// add_opening_journal - version 1 to 2. A ledger dumped before there was a
+// journal has balances but no entries explaining them. Post each client's
+// balances as an opening balance against the settlement account, along with
+// whether the account is locked.
+//
+fn add_opening_journal(ledger: &mut serde_json::Value) -> Result<(), PaymentError> {
+    let Some(ledger) = ledger.as_object_mut() else {
+        return Ok(());
+    };
+    if ledger.contains_key("journal") {
+        return Ok(());
+    }
+    let accounts: HashMap<u16, AccountStatus> = match ledger.get("by_client_id") {
+        Some(accounts) => serde_json::from_value(accounts.clone())?,
+        None => HashMap::new(),
+    };
+    let mut client_ids: Vec<u16> = accounts.keys().copied().collect();
+    client_ids.sort();
+    let mut journal = Journal::default();
+    for client_id in client_ids {
+        let account = &accounts[&client_id];
+        if account.available.is_zero() && account.held.is_zero() && !account.locked {
+            continue;
+        }
+        let (available, held) = (account.available, account.held);
+        let lines = vec![
+            JournalLine::for_change(JournalAccount::ClientAvailable(client_id), available),
+            JournalLine::for_change(JournalAccount::ClientHeld(client_id), held),
+            JournalLine::for_change(JournalAccount::Settlement, available + held),
+        ];
+        journal.post(JournalEntry {
+            client_id,
+            source: EntrySource::OpeningBalance {
+                locked: account.locked,
+            },
+            lines: lines.into_iter().filter(|line| !line.amount.is_zero()).collect(),
+        })?;
+    }
+    ledger.insert("journal".to_string(), serde_json::to_value(&journal)?);
+    Ok(())
+}
+
+//
+// add_dispute_amounts - version 1 to 2. Disputes were always for the whole
+// transaction, so its amount goes under whichever of disputed, resolved or
+// charged back its state says.
+//
+fn add_dispute_amounts(ledger: &mut serde_json::Value) {
+    let Some(records) = ledger
+        .get_mut("by_transaction_id")
+        .and_then(|records| records.as_object_mut())
+    else {
+        return;
+    };
+    for record in records.values_mut() {
+        let amount = record["transaction"]["amount"].clone();
+        let state = record["state"].as_str().unwrap_or_default().to_string();
+        for (field, in_state) in [
+            ("disputed", "Disputed"),
+            ("resolved", "Resolved"),
+            ("charged_back", "ChargedBack"),
+        ] {
+            record[field] = if state == in_state && !amount.is_null() {
+                amount.clone()
+            } else {
+                "0".into()
+            };
+        }
+    }
+}
+
+//
+// sorted_rows - accounts with their totals, sorted. Client ids are unique so
+// breaking ties on them makes the order fully determined.
+//
+fn sorted_rows<'a>(
+    accounts: impl Iterator<Item = &'a AccountStatus>,
+    sort_by: SortBy,
+) -> Vec<AccountStatusTotal> {
+    let mut rows: Vec<AccountStatusTotal> = accounts.map(AccountStatusTotal::new).collect();
+    match sort_by {
+        SortBy::Client => rows.sort_by_key(|row| row.client),
+        SortBy::Total => rows.sort_by_key(|row| (row.total, row.client)),
+        SortBy::Available => rows.sort_by_key(|row| (row.available, row.client)),
+        SortBy::Held => rows.sort_by_key(|row| (row.held, row.client)),
+    }
+    rows
+}
+
+//
+// write_rows - write rows as csv (with a header), a pretty json array or
+// json lines.
+//
+fn write_rows<W: std::io::Write, T: Serialize>(
+    mut wtr: W,
+    rows: &[T],
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(wtr);
+            for row in rows {
+                wtr.serialize(row)?;
+            }
+            wtr.flush()?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut wtr, rows)?;
+            writeln!(wtr)?;
+            wtr.flush()?;
+        }
+        OutputFormat::Jsonl => {
+            for row in rows {
+                serde_json::to_writer(&mut wtr, row)?;
+                writeln!(wtr)?;
+            }
+            wtr.flush()?;
+        }
+    }
+    Ok(())
 }
 
 impl Ledger {
     pub fn new() -> Self {
-        Ledger {
-            by_client_id: HashMap::new(),
-            by_transaction_id: HashMap::new(),
-        }
+        Self::default()
     }
     ///
     /// Is a given client id an existing client?
@@ -40,7 +421,8 @@ impl Ledger {
     ///
     /// Is a given transaction id already in the ledger?
     ///
-    /// Look to see if a transaction id is already in the ledger
+    /// Look to see if a transaction id is already in the ledger, or on a
+    /// shard's ledger in another shard's
     ///
     /// # Arguments
     ///
@@ -51,6 +433,62 @@ impl Ledger {
     ///
     pub fn is_existing_transaction(&self, trans_id: u32) -> bool {
         self.by_transaction_id.contains_key(&trans_id)
+            || self.foreign_transactions.contains_key(&trans_id)
+    }
+    ///
+    /// Record a tx_id that another shard has
+    ///
+    /// A shard only has its own clients' transactions. ShardedLedger tells
+    /// it about a tx_id another shard has before handing it a transaction
+    /// that refers to it, so reusing it is still a duplicate and disputing
+    /// it a client mismatch, as they would be in a single ledger.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `tx_id`: Transaction Id
+    /// * `client_id`: the client it belongs to
+    ///
+    pub fn add_foreign_transaction(&mut self, tx_id: u32, client_id: u16) {
+        self.foreign_transactions.insert(tx_id, client_id);
+    }
+    ///
+    /// Take in a client from another shard
+    ///
+    /// A transfer between clients on different shards, or a dispute or
+    /// resolve of one, needs both accounts. ShardedLedger takes the
+    /// destination's account off its shard and lends it to the sender's,
+    /// which processes the transaction as a single ledger would and then
+    /// gives the account back with `return_client`.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `client_id`: the client being lent
+    /// * `account`: its account, none if it doesn't exist yet
+    ///
+    pub fn borrow_client(&mut self, client_id: u16, account: Option<AccountStatus>) {
+        self.borrowed = Some(client_id);
+        if let Some(account) = account {
+            self.by_client_id.insert(client_id, account);
+        }
+    }
+    ///
+    /// Give back the client taken in by `borrow_client`
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    ///
+    /// # Returns
+    ///
+    /// * `Option<AccountStatus>`: the account as it is now, none if there
+    ///   wasn't one and the transaction didn't create it
+    ///
+    pub fn return_client(&mut self) -> Option<AccountStatus> {
+        self.borrowed
+            .take()
+            .and_then(|client_id| self.by_client_id.remove(&client_id))
     }
     ///
     /// dump ledger
@@ -66,33 +504,271 @@ impl Ledger {
     ///
     pub fn dump_ledger(&self, filename: &str) -> Result<(), Box<dyn Error>> {
         let temp_file = File::create(filename)?;
-        serde_json::to_writer_pretty(temp_file, self)?;
+        let dump = LedgerDumpRef {
+            version: LEDGER_FORMAT_VERSION,
+            ledger: self,
+        };
+        serde_json::to_writer_pretty(temp_file, &dump)?;
+        Ok(())
+    }
+    ///
+    /// load ledger
+    ///
+    /// Load a ledger written by `dump_ledger`, so processing can pick up
+    /// where an earlier run left off. Dumps from older format versions are
+    /// migrated forward first. A dump that's just the ledger, from before
+    /// dumps had a version header, is version 1.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename`: filename of the dump to read
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Ledger, PaymentError>`: `MissingLedgerVersion` or
+    ///   `UnsupportedLedgerVersion` for a header we can't use
+    ///
+    pub fn load_ledger(filename: &str) -> Result<Ledger, PaymentError> {
+        let file = File::open(filename)?;
+        let dump: serde_json::Value = serde_json::from_reader(BufReader::new(file))?;
+        let (version, ledger) = match dump {
+            serde_json::Value::Object(mut dump) if dump.contains_key("ledger") => {
+                let version = dump
+                    .get("version")
+                    .and_then(serde_json::Value::as_u64)
+                    .ok_or(PaymentError::MissingLedgerVersion)?;
+                let version = u32::try_from(version)
+                    .map_err(|_| PaymentError::UnsupportedLedgerVersion(u32::MAX))?;
+                (version, dump.remove("ledger").unwrap_or_default())
+            }
+            ledger => (1, ledger),
+        };
+        let ledger = migrate_ledger(version, ledger)?;
+        let ledger: Ledger = serde_json::from_value(ledger)?;
+        ledger.check_journal()?;
+        debug!(
+            "Loaded ledger {} with {} clients, {} transactions",
+            filename,
+            ledger.by_client_id.len(),
+            ledger.by_transaction_id.len()
+        );
+        Ok(ledger)
+    }
+    ///
+    /// Check the books
+    ///
+    /// Every journal entry has to balance, and every client's available and
+    /// held funds have to be what their journal accounts add up to.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), PaymentError>`: `UnbalancedJournal` or
+    ///   `JournalMismatch` for the first problem found
+    ///
+    pub fn check_journal(&self) -> Result<(), PaymentError> {
+        self.journal.check()?;
+        for account in self.by_client_id.values() {
+            let client_id = account.client;
+            let available = self.journal.balance(&JournalAccount::ClientAvailable(client_id));
+            let held = self.journal.balance(&JournalAccount::ClientHeld(client_id));
+            if available != account.available || held != account.held {
+                return Err(PaymentError::JournalMismatch(client_id));
+            }
+        }
         Ok(())
     }
     ///
     /// Write the clients to a `File`
     ///
-    /// Write the clients to stdout or file as a csv, with titles.
+    /// Write the clients to stdout or file as a csv, with titles. Clients are
+    /// always written in a fixed order so the output doesn't depend on how
+    /// the ledger was built (e.g. merged from shards).
     ///
     /// # Arguments
     ///
     /// * `self`: Self
     /// * `file`: File (either stdout or actual file)
+    /// * `sort_by`: order to write the clients in
     ///
     /// # Returns
     ///
     pub fn dump_client_csv<W: std::io::Write>(
         &self,
         wtr: &mut Writer<W>,
+        sort_by: SortBy,
     ) -> Result<(), Box<dyn Error>> {
-        for (_client_id, row) in &self.by_client_id {
-            let row = AccountStatusTotal::new(row);
+        for row in self.client_rows(sort_by) {
             wtr.serialize(row)?;
         }
         wtr.flush()?;
         Ok(())
     }
     ///
+    /// Write the clients in the given format
+    ///
+    /// Same rows and order as `dump_client_csv`, json amounts are strings.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `wtr`: where to write (either stdout or actual file)
+    /// * `format`: csv, json or jsonl
+    /// * `sort_by`: order to write the clients in
+    ///
+    /// # Returns
+    ///
+    pub fn dump_clients<W: std::io::Write>(
+        &self,
+        wtr: W,
+        format: OutputFormat,
+        sort_by: SortBy,
+    ) -> Result<(), Box<dyn Error>> {
+        write_rows(wtr, &self.client_rows(sort_by), format)
+    }
+    ///
+    /// A client's statement
+    ///
+    /// Every accepted transaction for the client in seq_num order, with the
+    /// available, held and total funds after each one, taken from the
+    /// journal. A ledger loaded from a dump that predates the journal starts
+    /// with an opening balance line.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `client_id`: client to write the statement for
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<StatementLine>, PaymentError>`: `UnknownClient` if the
+    ///   client isn't in the ledger
+    ///
+    pub fn statement(&self, client_id: u16) -> Result<Vec<StatementLine>, PaymentError> {
+        if !self.is_existing_client(client_id) {
+            return Err(PaymentError::UnknownClient(client_id));
+        }
+        let available_account = JournalAccount::ClientAvailable(client_id);
+        let held_account = JournalAccount::ClientHeld(client_id);
+        let mut entries: Vec<&JournalEntry> = self
+            .journal
+            .entries()
+            .iter()
+            .filter(|entry| entry.client_id == client_id)
+            .collect();
+        entries.sort_by_key(|entry| entry.seq_num());
+
+        let mut available = Decimal::ZERO;
+        let mut held = Decimal::ZERO;
+        let mut lines = Vec::with_capacity(entries.len());
+        for entry in entries {
+            available += entry.change(&available_account);
+            held += entry.change(&held_account);
+            let (moved, _) = entry.totals();
+            let (tx_type, tx, amount, dispute_state) = match &entry.source {
+                EntrySource::Transaction {
+                    tx_id,
+                    tx_type,
+                    dispute_state,
+                    ..
+                } => (
+                    tx_type.as_str(),
+                    Some(*tx_id),
+                    (!entry.lines.is_empty()).then_some(moved),
+                    *dispute_state,
+                ),
+                EntrySource::OpeningBalance { .. } => ("opening_balance", None, None, None),
+            };
+            lines.push(StatementLine {
+                seq_num: entry.seq_num(),
+                tx_type: tx_type.to_string(),
+                tx,
+                amount,
+                available,
+                held,
+                total: available + held,
+                dispute_state,
+            });
+        }
+        Ok(lines)
+    }
+    ///
+    /// Write a client's statement in the given format
+    ///
+    /// See `statement`. CSV has a header row, json is an array and jsonl one
+    /// object per line, as for the client balances.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `wtr`: where to write (either stdout or actual file)
+    /// * `client_id`: client to write the statement for
+    /// * `format`: csv, json or jsonl
+    ///
+    /// # Returns
+    ///
+    pub fn dump_statement<W: std::io::Write>(
+        &self,
+        wtr: W,
+        client_id: u16,
+        format: OutputFormat,
+    ) -> Result<(), Box<dyn Error>> {
+        write_rows(wtr, &self.statement(client_id)?, format)
+    }
+    ///
+    /// The client balances as they were at a seq_num
+    ///
+    /// Rebuilt by replaying the journal from the start. Only clients with
+    /// an accepted transaction by then are included, and a seq_num past
+    /// the end gives the current balances.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `seq_num`: point in time, transactions after it are left out
+    ///
+    /// # Returns
+    ///
+    /// * `HashMap<u16, AccountStatus>`: accounts by client id
+    ///
+    pub fn balances_as_of(&self, seq_num: u32) -> HashMap<u16, AccountStatus> {
+        self.journal.clients_as_of(seq_num)
+    }
+    ///
+    /// Write the client balances as they were at a seq_num
+    ///
+    /// Same rows as `dump_clients`, see `balances_as_of`.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `wtr`: where to write (either stdout or actual file)
+    /// * `seq_num`: point in time
+    /// * `format`: csv, json or jsonl
+    /// * `sort_by`: order to write the clients in
+    ///
+    /// # Returns
+    ///
+    pub fn dump_balances_as_of<W: std::io::Write>(
+        &self,
+        wtr: W,
+        seq_num: u32,
+        format: OutputFormat,
+        sort_by: SortBy,
+    ) -> Result<(), Box<dyn Error>> {
+        let balances = self.balances_as_of(seq_num);
+        write_rows(wtr, &sorted_rows(balances.values(), sort_by), format)
+    }
+    //
+    // client_rows - every client with its total, sorted.
+    //
+    fn client_rows(&self, sort_by: SortBy) -> Vec<AccountStatusTotal> {
+        sorted_rows(self.by_client_id.values(), sort_by)
+    }
+    ///
     /// Process a single transaction into the ledger
     ///
     /// A single transaction is passed into the function. It is processed into
@@ -105,27 +781,185 @@ impl Ledger {
     /// * `transaction`: Transaction
     ///
     /// # Returns
-    /// `Result<(), Box<dyn Error>>`: Ok(()) if all transactions were processed successfully
+    /// `Result<(), PaymentError>`: Ok(()) if the transaction was processed successfully
     ///
-    pub fn process_transaction(&mut self, transaction: &Transaction) -> Result<(), Box<dyn Error>> {
+    pub fn process_transaction(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
         // Check to see if the client exists
         if !self.is_existing_client(transaction.client_id) {
             self.add_client(transaction.client_id);
         }
         debug!("Processing transaction: {}", transaction.tx_id);
         // Now process the actual transaction
+        self.see_seq_num(transaction.seq_num);
+        let result = self
+            .check_timestamp(transaction)
+            .and_then(|()| match transaction.tx_type {
+                TransactionType::Chargeback => self.process_chargeback(transaction),
+                TransactionType::Deposit => self.process_deposit(transaction),
+                TransactionType::Dispute => self.process_dispute(transaction),
+                TransactionType::Resolve => self.process_resolve(transaction),
+                TransactionType::Transfer => self.process_transfer(transaction),
+                TransactionType::Withdrawl => self.process_withdrawl(transaction),
+                // Only an operator can freeze/unlock, see process_admin_transaction
+                TransactionType::Unlock | TransactionType::Freeze => {
+                    Err(PaymentError::UnauthorizedTransaction {
+                        tx_id: transaction.tx_id,
+                        tx_type: transaction.tx_type.clone(),
+                    })
+                }
+            });
+        self.stats.record(&transaction.tx_type, &result);
+        if let Err(err) = result {
+            return Err(self.log_rejected(false, transaction, err));
+        }
+        if let Some(timestamp) = transaction.timestamp {
+            self.last_timestamps.insert(transaction.client_id, timestamp);
+        }
+        // if we successfully processed a deposit, withdrawal or transfer,
+        // save it for later. Disputes, resolves and chargebacks only move the
+        // original along its lifecycle, they never replace it.
+        if matches!(
+            transaction.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawl | TransactionType::Transfer
+        ) {
+            self.by_transaction_id.insert(
+                transaction.tx_id,
+                TransactionRecord {
+                    transaction: transaction.clone(),
+                    state: DisputeState::Processed,
+                    disputed_at: None,
+                    disputed: Decimal::ZERO,
+                    resolved: Decimal::ZERO,
+                    charged_back: Decimal::ZERO,
+                },
+            );
+        }
+        Ok(())
+    }
+    ///
+    /// Process a single administrative transaction into the ledger
+    ///
+    /// Administrative transactions (freeze/unlock) come from the operator
+    /// input rather than the transaction feed, this is the only way a locked
+    /// account is ever reinstated. They aren't stored in the ledger as they
+    /// don't move any funds.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `transaction`: Transaction, must be an admin transaction type
+    ///
+    /// # Returns
+    /// `Result<(), PaymentError>`: Ok(()) if the transaction was processed successfully
+    ///
+    pub fn process_admin_transaction(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), PaymentError> {
+        debug!("Processing admin transaction: {}", transaction.tx_id);
+        self.see_seq_num(transaction.seq_num);
         let result = match transaction.tx_type {
-            TransactionType::Chargeback => self.process_chargeback(transaction),
-            TransactionType::Deposit => self.process_deposit(transaction),
-            TransactionType::Dispute => self.process_dispute(transaction),
-            TransactionType::Resolve => self.process_resolve(transaction),
-            TransactionType::Withdrawl => self.process_withdrawl(transaction),
+            TransactionType::Freeze => self.process_freeze(transaction),
+            TransactionType::Unlock => self.process_unlock(transaction),
+            _ => Err(PaymentError::UnauthorizedTransaction {
+                tx_id: transaction.tx_id,
+                tx_type: transaction.tx_type.clone(),
+            }),
         };
-        // if we successfully processed this transaction, save it for later.
-        if result.is_ok() {
-            self.by_transaction_id.insert(transaction.tx_id, transaction.clone());
+        self.stats.record(&transaction.tx_type, &result);
+        result.map_err(|err| self.log_rejected(true, transaction, err))
+    }
+    ///
+    /// Resolve disputes left open past the deadline
+    ///
+    /// Run once everything has been read. Each dispute is measured against
+    /// the latest seq_num the ledger has seen and the latest timestamp it
+    /// accepted. A resolve is posted for each one, numbered with that
+    /// seq_num, so it's in the journal and on the client's statement like
+    /// any other. Disputes from a dump that didn't record when they were
+    /// raised are left alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64, PaymentError>`: how many disputes were resolved
+    ///
+    pub fn resolve_expired_disputes(&mut self) -> Result<u64, PaymentError> {
+        let Some(seq_num) = self.last_seq_num else {
+            return Ok(0);
+        };
+        if !self.dispute_deadline.is_set() {
+            return Ok(0);
+        }
+        let now = EventTime {
+            seq_num,
+            timestamp: self.last_timestamps.values().max().copied(),
+        };
+        let mut expired: Vec<(u16, u32)> = self
+            .by_transaction_id
+            .values()
+            .filter(|record| {
+                record.state == DisputeState::Disputed
+                    && record
+                        .disputed_at
+                        .is_some_and(|at| self.dispute_deadline.is_closed(&at, &now))
+            })
+            .map(|record| (record.transaction.client_id, record.transaction.tx_id))
+            .collect();
+        // The same order every time, whatever order the map is in
+        expired.sort_unstable_by_key(|&(_, tx_id)| tx_id);
+        for &(client_id, tx_id) in &expired {
+            debug!("Dispute of transaction {} is past its deadline", tx_id);
+            // No timestamp, so it doesn't count as the client's latest
+            let resolve = Transaction {
+                seq_num,
+                tx_type: TransactionType::Resolve,
+                client_id,
+                tx_id,
+                amount: None,
+                timestamp: None,
+                destination_id: None,
+            };
+            self.process_resolve(&resolve)?;
+        }
+        self.stats.disputes_auto_resolved += expired.len() as u64;
+        Ok(expired.len() as u64)
+    }
+    //
+    // see_seq_num - keep last_seq_num up to date, whether or not the
+    // transaction is accepted it used up its seq_num.
+    //
+    fn see_seq_num(&mut self, seq_num: u32) {
+        self.last_seq_num = self.last_seq_num.max(Some(seq_num));
+    }
+    //
+    // check_timestamp - with reject_out_of_order set, refuse a transaction
+    // timestamped before its client's last accepted one. Equal timestamps
+    // are fine. Operator transactions don't come through here, they're run
+    // ahead of the feed and aren't ordered against it.
+    //
+    fn check_timestamp(&self, transaction: &Transaction) -> Result<(), PaymentError> {
+        if !self.reject_out_of_order {
+            return Ok(());
         }
-        result
+        let (Some(timestamp), Some(&last)) = (
+            transaction.timestamp,
+            self.last_timestamps.get(&transaction.client_id),
+        ) else {
+            return Ok(());
+        };
+        if timestamp < last {
+            return Err(PaymentError::TimestampOutOfOrder {
+                client_id: transaction.client_id,
+                tx_id: transaction.tx_id,
+                timestamp,
+                last,
+            });
+        }
+        Ok(())
     }
     ///
     /// add a client_id to the ledger, meaning we add an Account Status for this client.
@@ -134,6 +968,7 @@ impl Ledger {
         debug!("Adding client: {}", client_id);
         let client_account = AccountStatus::new(client_id);
         self.by_client_id.insert(client_id, client_account);
+        self.stats.clients_created += 1;
     }
     //
     // Chargeback
@@ -145,43 +980,44 @@ impl Ledger {
     // chargeback occurs the client's account should be immediately
     // frozen.
     //
-    fn process_chargeback(&mut self, transaction: &Transaction) -> Result<(), Box<dyn Error>> {
+    // A charged back withdrawal (DisputePolicy::WithdrawalsAsReversal) is
+    // reversed, the held funds are returned to the client's available funds.
+    // So is a charged back transfer, the destination gave the funds back
+    // when it was disputed.
+    //
+    fn process_chargeback(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
         debug!(
-            "Processing charge back for client: {} Tx_ID:{} Amt:{}",
+            "Processing charge back for client: {} Tx_ID:{} Amt:{:?}",
             transaction.client_id, transaction.tx_id, transaction.amount
         );
-        // Get the user account, it should be since we checked earlier.
-        let account = self.by_client_id.get_mut(&transaction.client_id);
-        if let Some(account) = account {
-            // Look for old transaction
-            let old_transaction = self.by_transaction_id.get(&transaction.tx_id);
-            if let Some(old_transaction) = old_transaction {
-                // Note: Do I need to check to see if the new amount == old amount?
-                if old_transaction.amount != transaction.amount {
-                    return Err(format!(
-                        "Old amount in transaction: {} was: {}, not equal to disputed amount {}",
-                        transaction.tx_id, old_transaction.amount, transaction.amount
-                    )
-                               .into());
-                } else {
-                    if old_transaction.tx_type != TransactionType::Dispute {
-                        return Err(format!(
-                            "Old amount in transaction: {} isn't in dispute", transaction.tx_id).into());
-                    }
-                    account.held -= transaction.amount;
-                    // Note: How does this ever get unlocked?
-                    debug!("Locking client: {}", transaction.client_id);
-                    account.locked = true;
-                }
-            } else {
-                return Err(format!(
-                    "Charge back transaction {} not found in ledger",
-                    transaction.tx_id
-                )
-                .into());
-            }
+        let (original_type, amount) =
+            self.check_dispute_transition(transaction, DisputeState::ChargedBack)?;
+        let client_id = transaction.client_id;
+        let mut account = self.account(client_id)?;
+        let lines = if matches!(
+            original_type,
+            TransactionType::Withdrawl | TransactionType::Transfer
+        ) {
+            transfer(
+                JournalAccount::ClientHeld(client_id),
+                JournalAccount::ClientAvailable(client_id),
+                amount,
+            )
         } else {
-            return Err(format!("Client {} not found in ledger", transaction.client_id).into());
+            transfer(
+                JournalAccount::ClientHeld(client_id),
+                JournalAccount::Settlement,
+                amount,
+            )
+        };
+        // An operator has to unlock this, see process_unlock
+        debug!("Locking client: {}", transaction.client_id);
+        let newly_locked = !account.locked;
+        account.locked = true;
+        self.commit(transaction, account, lines, Some(DisputeState::ChargedBack))?;
+        self.stats.charged_back += amount;
+        if newly_locked {
+            self.stats.accounts_locked += 1;
         }
         Ok(())
     }
@@ -191,22 +1027,35 @@ impl Ledger {
     // A deposit is a credit to the client's asset account, meaning it
     // should increase the available and total funds of the client account
     //
-    fn process_deposit(&mut self, transaction: &Transaction) -> Result<(), Box<dyn Error>> {
+    fn process_deposit(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
+        let amount = transaction
+            .amount
+            .ok_or(PaymentError::MissingAmount(transaction.tx_id))?;
+        if amount <= Decimal::ZERO {
+            return Err(PaymentError::InvalidAmount {
+                tx_id: transaction.tx_id,
+                amount,
+            });
+        }
         debug!(
             "Processing deposit for client: {} Tx_ID:{} Amt:{}",
-            transaction.client_id, transaction.tx_id, transaction.amount
+            transaction.client_id, transaction.tx_id, amount
         );
         // check to see if we've seen this transaction already
         if self.is_existing_transaction(transaction.tx_id) {
-            return Err(format!("Transaction {} already seen", transaction.tx_id).into());
+            return Err(PaymentError::DuplicateTransaction(transaction.tx_id));
         }
-        // Get the user account, it should be since we checked earlier.
-        let account = self.by_client_id.get_mut(&transaction.client_id);
-        if let Some(account) = account {
-            account.available += transaction.amount;
-        } else {
-            return Err(format!("Client {} not found in ledger", transaction.client_id).into());
+        let account = self.account(transaction.client_id)?;
+        if account.locked {
+            return Err(PaymentError::AccountLocked(transaction.client_id));
         }
+        let lines = transfer(
+            JournalAccount::Settlement,
+            JournalAccount::ClientAvailable(transaction.client_id),
+            amount,
+        );
+        self.commit(transaction, account, lines, None)?;
+        self.stats.deposited += amount;
         Ok(())
     }
     //
@@ -217,42 +1066,76 @@ impl Ledger {
     // reversed yet but the associated funds should be held. This means
     // that the clients available funds should decrease by the amount
     // disputed, their held funds should increase by the amount disputed,
-    // while their total funds should remain the same.
+    // while their total funds should remain the same. A dispute can be for
+    // part of the transaction, and there can be more than one of them.
     //
-    fn process_dispute(&mut self, transaction: &Transaction) -> Result<(), Box<dyn Error>> {
+    // Disputing a withdrawal depends on the ledger's DisputePolicy. As a
+    // reversal the withdrawn funds are already gone, so the amount being
+    // claimed back is added to held and available stays the same.
+    //
+    // A transfer can always be disputed, by the client that sent it. The
+    // disputed amount comes back from the destination's available funds
+    // and is held for the client, whatever the destination has left.
+    //
+    fn process_dispute(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
         debug!(
-            "Processing dispute for client: {} Tx_ID:{} Amt:{}",
+            "Processing dispute for client: {} Tx_ID:{} Amt:{:?}",
             transaction.client_id, transaction.tx_id, transaction.amount
         );
-
-        // Get the user account, it should be since we checked earlier.
-        let account = self.by_client_id.get_mut(&transaction.client_id);
-        if let Some(account) = account {
-            // Look for old transaction
-            let old_transaction = self.by_transaction_id.get(&transaction.tx_id);
-            if let Some(old_transaction) = old_transaction {
-                // Note: Do I need to check to see if the new amount == old amount?
-                if old_transaction.amount != transaction.amount {
-                    return Err(format!(
-                        "Old amount in transaction: {} was: {}, not equal to disputed amount {}",
-                        transaction.tx_id, old_transaction.amount, transaction.amount
-                    )
-                    .into());
-                } else {
-                    account.available -= transaction.amount;
-                    account.held += transaction.amount;
+        let (original_type, amount) =
+            self.check_dispute_transition(transaction, DisputeState::Disputed)?;
+        if original_type == TransactionType::Withdrawl {
+            match self.dispute_policy {
+                DisputePolicy::DepositsOnly => {
+                    debug!(
+                        "Ignoring dispute of withdrawal {} for client: {}",
+                        transaction.tx_id, transaction.client_id
+                    );
+                    let account = self.account(transaction.client_id)?;
+                    return self.commit(transaction, account, Vec::new(), None);
                 }
-            } else {
-                return Err(format!(
-                    "Disputed transaction {} not found in ledger",
-                    transaction.tx_id
-                )
-                .into());
+                DisputePolicy::Reject => {
+                    return Err(PaymentError::DisputeNotAllowed {
+                        tx_id: transaction.tx_id,
+                        tx_type: original_type,
+                    });
+                }
+                DisputePolicy::WithdrawalsAsReversal => {}
             }
-        } else {
-            return Err(format!("Client {} not found in ledger", transaction.client_id).into());
         }
-        Ok(())
+        let client_id = transaction.client_id;
+        let account = self.account(client_id)?;
+        if original_type == TransactionType::Transfer {
+            let destination = self.destination_of(transaction.tx_id)?;
+            let target = self.account(destination)?;
+            let postings = vec![
+                (
+                    account,
+                    transfer(
+                        JournalAccount::Clearing,
+                        JournalAccount::ClientHeld(client_id),
+                        amount,
+                    ),
+                ),
+                (
+                    target,
+                    transfer(
+                        JournalAccount::ClientAvailable(destination),
+                        JournalAccount::Clearing,
+                        amount,
+                    ),
+                ),
+            ];
+            return self.commit_postings(transaction, postings, Some(DisputeState::Disputed));
+        }
+        // A disputed withdrawal is a claim on the house, not on the client
+        let from = if original_type == TransactionType::Withdrawl {
+            JournalAccount::ChargebackLoss
+        } else {
+            JournalAccount::ClientAvailable(client_id)
+        };
+        let lines = transfer(from, JournalAccount::ClientHeld(client_id), amount);
+        self.commit(transaction, account, lines, Some(DisputeState::Disputed))
     }
     //
     // Resolve
@@ -264,68 +1147,514 @@ impl Ledger {
     // available funds should increase by the amount no longer
     // disputed, and their total funds should remain the same.
     //
-    fn process_resolve(&mut self, transaction: &Transaction) -> Result<(), Box<dyn Error>> {
+    // Resolving a disputed withdrawal means the withdrawal stands, the
+    // amount claimed back is dropped from held. Resolving a disputed transfer
+    // means it stands too, the held funds go back to the destination.
+    //
+    fn process_resolve(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
         debug!(
-            "Processing resolve for client: {} Tx_ID:{} Amt:{}",
+            "Processing resolve for client: {} Tx_ID:{} Amt:{:?}",
             transaction.client_id, transaction.tx_id, transaction.amount
         );
-
-        // Get the user account, it should be since we checked earlier.
-        let account = self.by_client_id.get_mut(&transaction.client_id);
-        if let Some(account) = account {
-            // Look for old transaction
-            let old_transaction = self.by_transaction_id.get(&transaction.tx_id);
-            if let Some(old_transaction) = old_transaction {
-                // Note: Do I need to check to see if the new amount == old amount?
-                if old_transaction.amount != transaction.amount {
-                    return Err(format!(
-                        "Old amount in transaction: {} was: {}, not equal to disputed amount {}",
-                        transaction.tx_id, old_transaction.amount, transaction.amount
-                    )
-                    .into());
-                } else {
-                    account.available += transaction.amount;
-                    account.held -= transaction.amount;
-                }
-            } else {
-                return Err(format!(
-                    "Resolved transaction {} not found in ledger",
-                    transaction.tx_id
-                )
-                .into());
+        let (original_type, amount) =
+            self.check_dispute_transition(transaction, DisputeState::Resolved)?;
+        let client_id = transaction.client_id;
+        let account = self.account(client_id)?;
+        if original_type == TransactionType::Transfer {
+            let destination = self.destination_of(transaction.tx_id)?;
+            let target = self.account(destination)?;
+            let postings = vec![
+                (
+                    account,
+                    transfer(
+                        JournalAccount::ClientHeld(client_id),
+                        JournalAccount::Clearing,
+                        amount,
+                    ),
+                ),
+                (
+                    target,
+                    transfer(
+                        JournalAccount::Clearing,
+                        JournalAccount::ClientAvailable(destination),
+                        amount,
+                    ),
+                ),
+            ];
+            return self.commit_postings(transaction, postings, Some(DisputeState::Resolved));
+        }
+        let to = if original_type == TransactionType::Withdrawl {
+            JournalAccount::ChargebackLoss
+        } else {
+            JournalAccount::ClientAvailable(client_id)
+        };
+        let lines = transfer(JournalAccount::ClientHeld(client_id), to, amount);
+        self.commit(transaction, account, lines, Some(DisputeState::Resolved))
+    }
+    //
+    // check_dispute_transition
+    //
+    // Disputes, resolves and chargebacks all refer back to an earlier
+    // transaction by tx_id. Make sure that transaction exists, belongs to
+    // the same client and that moving it to the `next` state is legal.
+    //
+    // A dispute is for its amount, or without one everything not yet
+    // disputed. It can't be for more than that, so all the disputes of a
+    // transaction add up to at most its amount. A resolve or chargeback is
+    // always for everything under dispute. In strict mode a supplied amount
+    // has to be exactly that, and a dispute for the whole original.
+    //
+    // Returns the type of the original transaction and the amount that gets
+    // held/released/charged back.
+    //
+    fn check_dispute_transition(
+        &self,
+        transaction: &Transaction,
+        next: DisputeState,
+    ) -> Result<(TransactionType, Decimal), PaymentError> {
+        // Only the client that owns a transaction gets to dispute it
+        if let Some(&owner) = self.foreign_transactions.get(&transaction.tx_id) {
+            return Err(PaymentError::ClientMismatch {
+                tx_id: transaction.tx_id,
+                owner,
+                client_id: transaction.client_id,
+            });
+        }
+        let record = self
+            .by_transaction_id
+            .get(&transaction.tx_id)
+            .ok_or(PaymentError::UnknownTransaction(transaction.tx_id))?;
+        if record.transaction.client_id != transaction.client_id {
+            return Err(PaymentError::ClientMismatch {
+                tx_id: transaction.tx_id,
+                owner: record.transaction.client_id,
+                client_id: transaction.client_id,
+            });
+        }
+        let original_amount = record
+            .transaction
+            .amount
+            .ok_or(PaymentError::MissingAmount(transaction.tx_id))?;
+        // Another dispute, of what's left after the earlier ones (still open
+        // or resolved)
+        let disputing_again = matches!(
+            record.state,
+            DisputeState::Disputed | DisputeState::Resolved
+        ) && next == DisputeState::Disputed
+            && record.undisputed() > Decimal::ZERO;
+        if !disputing_again && !record.state.can_transition_to(next) {
+            return Err(PaymentError::InvalidStateTransition {
+                tx_id: transaction.tx_id,
+                from: record.state,
+                to: next,
+            });
+        }
+        let expected = if next == DisputeState::Disputed {
+            original_amount
+        } else {
+            record.disputed
+        };
+        if let Some(amount) = transaction.amount {
+            if self.strict_amounts && amount != expected {
+                return Err(PaymentError::AmountMismatch {
+                    tx_id: transaction.tx_id,
+                    expected,
+                    found: amount,
+                });
             }
+        }
+        let amount = if next == DisputeState::Disputed {
+            let undisputed = record.undisputed();
+            let requested = transaction.amount.unwrap_or(undisputed);
+            if requested <= Decimal::ZERO || requested > undisputed {
+                return Err(PaymentError::InvalidDisputeAmount {
+                    tx_id: transaction.tx_id,
+                    undisputed,
+                    requested,
+                });
+            }
+            requested
         } else {
-            return Err(format!("Client {} not found in ledger", transaction.client_id).into());
+            record.disputed
+        };
+        // Too late to dispute, or to charge back a dispute that's been open
+        // too long. A late resolve is still let through.
+        let closed = match next {
+            DisputeState::Disputed => self
+                .dispute_window
+                .is_closed(&record.transaction.event_time(), &transaction.event_time()),
+            DisputeState::ChargedBack => record.disputed_at.is_some_and(|disputed_at| {
+                self.dispute_deadline
+                    .is_closed(&disputed_at, &transaction.event_time())
+            }),
+            _ => false,
+        };
+        if closed {
+            return Err(PaymentError::DisputeWindowClosed {
+                tx_id: transaction.tx_id,
+                tx_type: transaction.tx_type.clone(),
+            });
+        }
+        Ok((record.transaction.tx_type.clone(), amount))
+    }
+    //
+    // set_dispute_state - move a stored transaction to a new lifecycle state,
+    // the transition has already been checked. `amount` is how much of it
+    // was disputed, or released from dispute. `at` is when, the dispute
+    // that opens it (with nothing else under dispute) is remembered for the
+    // deadline.
+    //
+    fn set_dispute_state(
+        &mut self,
+        tx_id: u32,
+        state: DisputeState,
+        amount: Decimal,
+        at: EventTime,
+    ) {
+        if let Some(record) = self.by_transaction_id.get_mut(&tx_id) {
+            debug!("Transaction {} {:?} -> {:?}", tx_id, record.state, state);
+            record.state = state;
+            match state {
+                DisputeState::Disputed => {
+                    if record.disputed.is_zero() {
+                        record.disputed_at = Some(at);
+                    }
+                    record.disputed += amount;
+                }
+                DisputeState::Resolved => {
+                    record.disputed -= amount;
+                    record.resolved += amount;
+                }
+                DisputeState::ChargedBack => {
+                    record.disputed -= amount;
+                    record.charged_back += amount;
+                }
+                DisputeState::Processed => {}
+            }
+        }
+    }
+    //
+    // account - a copy of the client's account for a transaction to work
+    // on, it only goes back into the ledger through commit.
+    //
+    fn account(&self, client_id: u16) -> Result<AccountStatus, PaymentError> {
+        self.by_client_id
+            .get(&client_id)
+            .cloned()
+            .ok_or(PaymentError::UnknownClient(client_id))
+    }
+    //
+    // commit - the last step of every accepted transaction. `account` is
+    // the client's account with any change to its lock applied, `lines` the
+    // journal entry that moves the funds (if any) and `dispute_state` where
+    // the referenced transaction moves to (if any). The client's balances
+    // only ever change by posting to the journal.
+    //
+    // If there is a write-ahead log the transaction and its outcome are
+    // written to it first, a failed write leaves the ledger untouched.
+    //
+    fn commit(
+        &mut self,
+        transaction: &Transaction,
+        account: AccountStatus,
+        lines: Vec<JournalLine>,
+        dispute_state: Option<DisputeState>,
+    ) -> Result<(), PaymentError> {
+        self.commit_postings(transaction, vec![(account, lines)], dispute_state)
+    }
+    //
+    // commit_postings - commit for a transaction that moves funds for more
+    // than one client, a transfer. Each posting is a client's account and
+    // the lines of their own journal entry, the transaction's client first.
+    // Every entry is checked before any is posted, so they all go in or none
+    // do. The write-ahead log has the first client's account.
+    //
+    fn commit_postings(
+        &mut self,
+        transaction: &Transaction,
+        postings: Vec<(AccountStatus, Vec<JournalLine>)>,
+        dispute_state: Option<DisputeState>,
+    ) -> Result<(), PaymentError> {
+        // A new deposit/withdrawal/transfer starts out Processed
+        let entry_state = match transaction.tx_type {
+            TransactionType::Deposit | TransactionType::Withdrawl | TransactionType::Transfer => {
+                Some(DisputeState::Processed)
+            }
+            _ => dispute_state,
+        };
+        let mut held = Decimal::ZERO;
+        let mut entries = Vec::with_capacity(postings.len());
+        for (mut account, lines) in postings {
+            let mut entry = JournalEntry::for_transaction(transaction, lines, entry_state);
+            entry.client_id = account.client;
+            entry.check_balanced()?;
+            let change = entry.change(&JournalAccount::ClientHeld(account.client));
+            account.available += entry.change(&JournalAccount::ClientAvailable(account.client));
+            account.held += change;
+            held += change;
+            entries.push((account, entry));
+        }
+        if let (Some(ref mut wal), Some((account, _))) = (&mut self.wal, entries.first()) {
+            let outcome = WalOutcome::Accepted {
+                account: account.clone(),
+                dispute_state,
+            };
+            self.last_lsn = wal.append(transaction.tx_type.is_admin(), transaction, outcome)?;
+        }
+        for (account, entry) in entries {
+            self.journal.post(entry)?;
+            self.by_client_id.insert(account.client, account);
+        }
+        // Whatever the policy, what's held is the part of the original that
+        // was disputed or released
+        if let Some(state) = dispute_state {
+            self.set_dispute_state(
+                transaction.tx_id,
+                state,
+                held.abs(),
+                transaction.event_time(),
+            );
         }
         Ok(())
     }
     //
+    // log_rejected - rejected transactions don't change any balances, but
+    // they're still written to the write-ahead log (if there is one) so a
+    // replay sees exactly what this ledger saw.
+    //
+    fn log_rejected(
+        &mut self,
+        admin: bool,
+        transaction: &Transaction,
+        err: PaymentError,
+    ) -> PaymentError {
+        if let Some(ref mut wal) = self.wal {
+            let outcome = WalOutcome::Rejected {
+                reason: err.to_string(),
+            };
+            match wal.append(admin, transaction, outcome) {
+                Ok(lsn) => self.last_lsn = lsn,
+                Err(wal_err) => return wal_err,
+            }
+        }
+        err
+    }
+    ///
+    /// Attach a write-ahead log
+    ///
+    /// From now on every transaction is written to the log, accepted ones
+    /// before they change the ledger. See `wal::recover` for reading it back.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `wal`: an open Wal
+    ///
+    pub fn attach_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+    ///
+    /// Detach the write-ahead log, if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Wal>`: the log that was attached
+    ///
+    pub fn take_wal(&mut self) -> Option<Wal> {
+        self.wal.take()
+    }
+    //
     // Withdrawal
     //
     // A withdraw is a debit to the client's asset account, meaning it
     // should decrease the available and total funds of the client
     // account.
     //
-    fn process_withdrawl(&mut self, transaction: &Transaction) -> Result<(), Box<dyn Error>> {
+    fn process_withdrawl(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
+        let amount = transaction
+            .amount
+            .ok_or(PaymentError::MissingAmount(transaction.tx_id))?;
+        if amount <= Decimal::ZERO {
+            return Err(PaymentError::InvalidAmount {
+                tx_id: transaction.tx_id,
+                amount,
+            });
+        }
         debug!(
             "Processing withdrawl for client: {} Tx_ID:{} Amt:{}",
-            transaction.client_id, transaction.tx_id, transaction.amount
+            transaction.client_id, transaction.tx_id, amount
         );
         // check to see if we've seen this transaction already
         if self.is_existing_transaction(transaction.tx_id) {
-            return Err(format!("Transaction {} already seen", transaction.tx_id).into());
+            return Err(PaymentError::DuplicateTransaction(transaction.tx_id));
         }
-        // Get the user account, it should be since we checked earlier.
-        let account = self.by_client_id.get_mut(&transaction.client_id);
-        if let Some(account) = account {
-            // TODO: Do I need checks here to make sure it's not locked.
-            if account.available >= transaction.amount {
-                account.available -= transaction.amount;
+        let account = self.account(transaction.client_id)?;
+        if account.locked {
+            return Err(PaymentError::AccountLocked(transaction.client_id));
+        }
+        if account.available < amount {
+            self.stats.declined_withdrawals += 1;
+            return Err(PaymentError::InsufficientFunds {
+                client_id: transaction.client_id,
+                tx_id: transaction.tx_id,
+                available: account.available,
+                requested: amount,
+            });
+        }
+        let lines = transfer(
+            JournalAccount::ClientAvailable(transaction.client_id),
+            JournalAccount::Settlement,
+            amount,
+        );
+        self.commit(transaction, account, lines, None)?;
+        self.stats.withdrawn += amount;
+        Ok(())
+    }
+    //
+    // Transfer
+    //
+    // A transfer moves funds from the client's available funds to another
+    // client's, the destination, as one transaction: both sides are posted
+    // or neither is. Like a withdrawal it's refused if the client is locked
+    // or hasn't the funds available, and like a deposit if the destination
+    // is locked. The destination is created if we haven't seen them yet.
+    //
+    fn process_transfer(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
+        let amount = transaction
+            .amount
+            .ok_or(PaymentError::MissingAmount(transaction.tx_id))?;
+        if amount <= Decimal::ZERO {
+            return Err(PaymentError::InvalidTransferAmount {
+                tx_id: transaction.tx_id,
+                amount,
+            });
+        }
+        let destination = self.check_destination(transaction)?;
+        debug!(
+            "Processing transfer for client: {} Tx_ID:{} Amt:{} To:{}",
+            transaction.client_id, transaction.tx_id, amount, destination
+        );
+        // check to see if we've seen this transaction already
+        if self.is_existing_transaction(transaction.tx_id) {
+            return Err(PaymentError::DuplicateTransaction(transaction.tx_id));
+        }
+        let account = self.account(transaction.client_id)?;
+        if account.locked {
+            return Err(PaymentError::AccountLocked(transaction.client_id));
+        }
+        if account.available < amount {
+            return Err(PaymentError::InsufficientFunds {
+                client_id: transaction.client_id,
+                tx_id: transaction.tx_id,
+                available: account.available,
+                requested: amount,
+            });
+        }
+        let new_client = !self.is_existing_client(destination);
+        let target = self
+            .account(destination)
+            .unwrap_or_else(|_| AccountStatus::new(destination));
+        if target.locked {
+            return Err(PaymentError::AccountLocked(destination));
+        }
+        let postings = vec![
+            (
+                account,
+                transfer(
+                    JournalAccount::ClientAvailable(transaction.client_id),
+                    JournalAccount::Clearing,
+                    amount,
+                ),
+            ),
+            (
+                target,
+                transfer(
+                    JournalAccount::Clearing,
+                    JournalAccount::ClientAvailable(destination),
+                    amount,
+                ),
+            ),
+        ];
+        self.commit_postings(transaction, postings, None)?;
+        if new_client {
+            debug!("Added client: {}", destination);
+            self.stats.clients_created += 1;
+        }
+        self.stats.transferred += amount;
+        Ok(())
+    }
+    //
+    // check_destination - the client a transfer goes to. It has to have one,
+    // that isn't the client sending it and, on a shard, is on the same
+    // shard or has been lent to it.
+    //
+    fn check_destination(&self, transaction: &Transaction) -> Result<u16, PaymentError> {
+        let destination = transaction
+            .destination_id
+            .ok_or(PaymentError::MissingDestination(transaction.tx_id))?;
+        if destination == transaction.client_id {
+            return Err(PaymentError::SelfTransfer {
+                tx_id: transaction.tx_id,
+                client_id: transaction.client_id,
+            });
+        }
+        if let Some((index, shards)) = self.shard {
+            if destination as usize % shards != index && self.borrowed != Some(destination) {
+                return Err(PaymentError::CrossShardTransfer {
+                    tx_id: transaction.tx_id,
+                    client_id: transaction.client_id,
+                    destination,
+                });
             }
-        } else {
-            return Err(format!("Client {} not found in ledger", transaction.client_id).into());
         }
-
+        Ok(destination)
+    }
+    //
+    // destination_of - the destination of a stored transfer, for a dispute,
+    // resolve or chargeback of it.
+    //
+    fn destination_of(&self, tx_id: u32) -> Result<u16, PaymentError> {
+        let record = self
+            .by_transaction_id
+            .get(&tx_id)
+            .ok_or(PaymentError::UnknownTransaction(tx_id))?;
+        self.check_destination(&record.transaction)
+    }
+    //
+    // Freeze
+    //
+    // An operator has decided the account should be locked, e.g. while a
+    // fraud review is underway. The client is created if we haven't seen
+    // them yet so an account can be frozen before it's first used.
+    //
+    fn process_freeze(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
+        debug!("Freezing client: {}", transaction.client_id);
+        if !self.is_existing_client(transaction.client_id) {
+            self.add_client(transaction.client_id);
+        }
+        let mut account = self.account(transaction.client_id)?;
+        let newly_locked = !account.locked;
+        account.locked = true;
+        self.commit(transaction, account, Vec::new(), None)?;
+        if newly_locked {
+            self.stats.accounts_locked += 1;
+        }
         Ok(())
     }
+    //
+    // Unlock
+    //
+    // An operator has reviewed a locked account (chargeback or freeze) and
+    // reinstated it. Balances are left untouched.
+    //
+    fn process_unlock(&mut self, transaction: &Transaction) -> Result<(), PaymentError> {
+        debug!("Unlocking client: {}", transaction.client_id);
+        let mut account = self.account(transaction.client_id)?;
+        account.locked = false;
+        self.commit(transaction, account, Vec::new(), None)
+    }
 }
@@ -1,4 +1,10 @@
 pub mod account;
 pub mod args;
+pub mod error;
+pub mod journal;
 pub mod ledger;
-pub mod transaction;
\ No newline at end of file
+pub mod rejects;
+pub mod shard;
+pub mod summary;
+pub mod transaction;
+pub mod wal;
\ No newline at end of file
@@ -1,16 +1,100 @@
 use clap::Parser;
-use csv::Writer;
 use env_logger::Builder;
 use log::{debug, error};
 use std::io;
 
 use payment_engine::args::Args;
-use payment_engine::ledger::Ledger;
+use payment_engine::error::PaymentError;
+use payment_engine::ledger::{DisputeWindow, Ledger};
+use payment_engine::rejects::RejectWriter;
+use payment_engine::shard::ShardedLedger;
+use payment_engine::summary::{ReadSummary, RunSummary};
 use payment_engine::transaction;
-use payment_engine::transaction::Transaction;
+use payment_engine::transaction::{InputFormat, ReadSession, Transaction};
+use payment_engine::wal;
+use payment_engine::wal::Wal;
+
+//
+// read_input - run the operator file (if any) and then each of the input
+// files, in order, through `process_func`, which is told which of the two
+// each transaction came from. Operator freeze/unlock transactions are
+// applied first, they are the only place admin transactions are accepted
+// from. All the files are read in the one `session`, so seq_nums keep
+// counting up across them. Rejected rows go to `rejects` if it's given.
+// Returns what was read over all the files.
+//
+fn read_input<F>(
+    args: &Args,
+    session: &mut ReadSession,
+    mut rejects: Option<&mut RejectWriter>,
+    mut process_func: F,
+) -> ReadSummary
+where
+    F: FnMut(bool, Transaction) -> Result<(), PaymentError>,
+{
+    let mut summary = ReadSummary::default();
+    if let Some(ref admin) = args.admin {
+        match transaction::process_file(
+            admin,
+            InputFormat::Csv,
+            session,
+            |transaction| process_func(true, transaction),
+            !args.stop_on_error,
+            rejects.as_deref_mut(),
+        ) {
+            Ok(read) => summary.merge(&read),
+            Err(e) => {
+                error!("Error processing admin CSV: {}", e);
+                exit_after_flush(rejects);
+            }
+        }
+    }
+
+    let files = match transaction::expand_inputs(&args.names) {
+        Ok(files) => files,
+        Err(e) => {
+            error!("Error finding input files: {}", e);
+            exit_after_flush(rejects);
+        }
+    };
+    for file in files {
+        debug!("Processing {}", file);
+        match transaction::process_file(
+            &file,
+            args.input_format,
+            session,
+            |transaction| process_func(false, transaction),
+            !args.stop_on_error,
+            rejects.as_deref_mut(),
+        ) {
+            Ok(read) => summary.merge(&read),
+            Err(e) => {
+                error!("Error processing CSV {}: {}", file, e);
+                exit_after_flush(rejects);
+            }
+        }
+    }
+    summary
+}
+
+//
+// exit_after_flush - stop with an error, making sure the rejects written so
+// far (including the one that stopped us) make it to the file first.
+//
+fn exit_after_flush(rejects: Option<&mut RejectWriter>) -> ! {
+    if let Some(rejects) = rejects {
+        if let Err(e) = rejects.flush() {
+            error!("Error writing rejects: {}", e);
+        }
+    }
+    std::process::exit(1);
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    if let Err(e) = args.validate() {
+        e.exit();
+    }
 
     let mut builder = Builder::new();
 
@@ -30,22 +114,118 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     debug!("processing");
 
-    let mut ledger = Ledger::new();
+    // Either pick up from an earlier run's state or start empty
+    let mut ledger = match args.resume_from {
+        Some(ref resume_from) => match Ledger::load_ledger(resume_from) {
+            Ok(ledger) => ledger,
+            Err(e) => {
+                error!("Error loading ledger {}: {}", resume_from, e);
+                std::process::exit(1);
+            }
+        },
+        None => Ledger::new(),
+    };
+    ledger.strict_amounts = args.strict_amounts;
+    ledger.dispute_policy = args.dispute_policy;
+    ledger.reject_out_of_order = args.reject_out_of_order;
+    ledger.dispute_window = DisputeWindow {
+        seconds: args.dispute_window,
+        seq_nums: args.dispute_window_seq,
+    };
+    ledger.dispute_deadline = DisputeWindow {
+        seconds: args.dispute_deadline,
+        seq_nums: args.dispute_deadline_seq,
+    };
+
+    // Replay anything the log has past the starting point, then keep
+    // logging to it for this run.
+    if let Some(ref wal_file) = args.wal {
+        ledger = match wal::recover(ledger, wal_file) {
+            Ok(ledger) => ledger,
+            Err(e) => {
+                error!("Error recovering from write-ahead log {}: {}", wal_file, e);
+                std::process::exit(1);
+            }
+        };
+        ledger.attach_wal(Wal::open(wal_file)?);
+    }
 
-    let process_func = |transaction: Transaction| ledger.process_transaction(&transaction);
+    // Carry on numbering transactions from where the ledger left off
+    let mut session = ReadSession::resume(ledger.last_seq_num);
 
-    if let Err(e) = transaction::process_file(
-        args.name.to_str().unwrap(),
-        process_func,
-        !args.stop_on_error,
-    ) {
-        error!("Error processing CSV: {}", e);
+    let mut rejects = match args.rejects {
+        Some(ref rejects) => Some(RejectWriter::create(rejects)?),
+        None => None,
+    };
+
+    let (mut ledger, read) = if args.threads > 1 {
+        let mut sharded = ShardedLedger::new(ledger, args.threads as usize, !args.stop_on_error);
+        let read = read_input(&args, &mut session, rejects.as_mut(), |admin, transaction| {
+            if admin {
+                sharded.process_admin_transaction(transaction)
+            } else {
+                sharded.process_transaction(transaction)
+            }
+        });
+        match sharded.finish() {
+            Ok(ledger) => (ledger, read),
+            Err(e) => {
+                error!("Error processing CSV: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let read = read_input(&args, &mut session, rejects.as_mut(), |admin, transaction| {
+            if admin {
+                ledger.process_admin_transaction(&transaction)
+            } else {
+                ledger.process_transaction(&transaction)
+            }
+        });
+        (ledger, read)
+    };
+    if let Some(ref mut rejects) = rejects {
+        rejects.flush()?;
+    }
+
+    // Disputes that have been open too long are resolved now that everything
+    // has been read
+    if let Err(e) = ledger.resolve_expired_disputes() {
+        error!("Error resolving expired disputes: {}", e);
+        std::process::exit(1);
+    }
+
+    // The balances are only worth writing if the books balance
+    if let Err(e) = ledger.check_journal() {
+        error!("Ledger doesn't balance: {}", e);
         std::process::exit(1);
     }
 
-    // since we processed everything given to us, output the client list
-    let mut wtr = Writer::from_writer(io::stdout());
-    ledger.dump_client_csv(&mut wtr)?;
+    // since we processed everything given to us, output the client list, the
+    // client list at an earlier point, or the one client's statement if
+    // that's what was asked for
+    match (args.statement, args.as_of) {
+        (Some(client_id), _) => {
+            if let Err(e) = ledger.dump_statement(io::stdout(), client_id, args.output_format) {
+                error!("Error writing statement for client {}: {}", client_id, e);
+                std::process::exit(1);
+            }
+        }
+        (None, Some(seq_num)) => ledger.dump_balances_as_of(
+            io::stdout(),
+            seq_num,
+            args.output_format,
+            args.sort_by,
+        )?,
+        (None, None) => ledger.dump_clients(io::stdout(), args.output_format, args.sort_by)?,
+    }
+
+    // What the run did, to the summary file if there is one, else stderr
+    let summary = serde_json::to_string_pretty(&RunSummary::new(&read, &ledger))?;
+    match args.summary {
+        Some(ref summary_file) => std::fs::write(summary_file, summary + "\n")?,
+        None => eprintln!("{}", summary),
+    }
 
     // if they asked for the internal state to be written, then log it.
     if let Some(ref statelog) = args.statelog {
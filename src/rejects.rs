@@ -0,0 +1,151 @@
+use csv::{StringRecord, Writer, WriterBuilder};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+
+use crate::error::PaymentError;
+
+//
+// Rejected transactions
+//
+// Every row that's rejected, either because it couldn't be parsed or because
+// the ledger refused it, can be written to a dead-letter CSV so it can be
+// corrected and resubmitted. The columns are where it was read from (file
+// and line) and why it was rejected, followed by the row's fields as they
+// were read.
+//
+
+const REJECT_HEADERS: [&str; 11] = [
+    "file",
+    "line",
+    "seq_num",
+    "reason_code",
+    "message",
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "destination",
+];
+
+#[derive(Debug, Serialize)]
+struct RejectRow<'a> {
+    file: &'a str,
+    line: u32,
+    seq_num: Option<u32>, // empty if the row didn't parse far enough to get one
+    reason_code: &'static str,
+    message: String,
+    #[serde(rename = "type")]
+    tx_type: &'a str,
+    client: &'a str,
+    tx: &'a str,
+    amount: &'a str,
+    timestamp: &'a str,
+    destination: &'a str,
+}
+
+pub struct RejectWriter {
+    wtr: Writer<Box<dyn Write>>,
+    source: String, // input currently being read
+}
+
+impl RejectWriter {
+    ///
+    /// Create a rejects file, with its header row
+    ///
+    /// # Arguments
+    ///
+    /// * `filename`: file to create (truncated if it exists)
+    ///
+    /// # Returns
+    ///
+    /// * `Result<RejectWriter, PaymentError>`
+    ///
+    pub fn create(filename: &str) -> Result<RejectWriter, PaymentError> {
+        RejectWriter::from_writer(Box::new(File::create(filename)?))
+    }
+    ///
+    /// Write rejects to anything that can be written to, with a header row
+    ///
+    /// # Arguments
+    ///
+    /// * `wtr`: where to write
+    ///
+    /// # Returns
+    ///
+    /// * `Result<RejectWriter, PaymentError>`
+    ///
+    pub fn from_writer(wtr: Box<dyn Write>) -> Result<RejectWriter, PaymentError> {
+        let mut wtr = WriterBuilder::new().has_headers(false).from_writer(wtr);
+        wtr.write_record(REJECT_HEADERS)?;
+        Ok(RejectWriter {
+            wtr,
+            source: String::new(),
+        })
+    }
+    ///
+    /// Set the input that following rejects came from
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `source`: file name (or `-` for stdin)
+    ///
+    pub fn set_source(&mut self, source: &str) {
+        self.source = source.to_string();
+    }
+    ///
+    /// Write a rejected row
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `line`: line of the current input the row starts on
+    /// * `seq_num`: the transaction's seq_num, if it got that far
+    /// * `record`: the row's fields, if it could be split into fields
+    /// * `err`: why it was rejected
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), PaymentError>`
+    ///
+    pub fn write(
+        &mut self,
+        line: u32,
+        seq_num: Option<u32>,
+        record: Option<&StringRecord>,
+        err: &PaymentError,
+    ) -> Result<(), PaymentError> {
+        let field = |index: usize| record.and_then(|record| record.get(index)).unwrap_or("");
+        self.wtr.serialize(RejectRow {
+            file: &self.source,
+            line,
+            seq_num,
+            reason_code: err.reason_code(),
+            message: err.to_string(),
+            tx_type: field(0),
+            client: field(1),
+            tx: field(2),
+            amount: field(3),
+            timestamp: field(4),
+            destination: field(5),
+        })?;
+        Ok(())
+    }
+    ///
+    /// Flush anything buffered out to the file
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), PaymentError>`
+    ///
+    pub fn flush(&mut self) -> Result<(), PaymentError> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
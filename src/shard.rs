@@ -0,0 +1,475 @@
+use log::error;
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::account::AccountStatus;
+use crate::error::PaymentError;
+use crate::journal::{Journal, JournalEntry};
+use crate::ledger::Ledger;
+use crate::transaction::{Transaction, TransactionType};
+
+//
+// Sharded processing
+//
+// Nearly every transaction only touches one client's account, so clients can
+// be split across worker threads that each own a Ledger of their own. The
+// reader routes a transaction to shard `client_id % shards`, which keeps
+// each client's transactions in the order they were read. When the input
+// is done the shards are merged back into a single Ledger.
+//
+// Shards don't see each other's transactions, so the reader keeps track of
+// which shard each tx_id went to. A transaction that refers to a tx_id on
+// another shard (reusing it, or disputing another client's transaction)
+// waits while the reader asks that shard whether it has it and whose it
+// is. If it has, the transaction's shard is told before it gets the
+// transaction, so it's rejected as a duplicate or a client mismatch just as
+// it would be by a single ledger.
+//
+// A transfer touches two clients. When they're on different shards the
+// reader waits while the destination's shard lends its account to the
+// sender's, which processes the transfer as a single ledger would, and
+// then gives it back. The reader remembers the destination of every such
+// transfer, so a dispute or resolve of it (which moves funds back from or
+// to the destination) is handled the same way. A chargeback of one only
+// touches the sender. Nothing else is sent to either shard while a client
+// is lent, so both see every transaction in the order it was read.
+//
+
+// How many transactions can be queued up for a shard before the reader waits
+const SHARD_QUEUE_DEPTH: usize = 1024;
+
+enum ShardMessage {
+    // A transaction, and whether it came from the operator input
+    Transaction(bool, Transaction),
+    // Another shard has this tx_id, it's the client's
+    ForeignTransaction { tx_id: u32, client_id: u16 },
+    // Whose is this tx_id, if this shard has it?
+    FindTransaction {
+        tx_id: u32,
+        reply: Sender<Option<u16>>,
+    },
+    // Take the client off this shard for a transaction on another one, the
+    // reply is its account if it has one
+    LendClient {
+        client_id: u16,
+        reply: Sender<Option<AccountStatus>>,
+    },
+    // A transaction that needs a client lent by another shard, the reply is
+    // whether it was accepted and the client's account after it
+    Borrowing {
+        transaction: Transaction,
+        client_id: u16,
+        account: Option<AccountStatus>,
+        reply: Sender<(bool, Option<AccountStatus>)>,
+    },
+    // A lent client coming back
+    ReturnClient(AccountStatus),
+}
+
+struct Shard {
+    sender: SyncSender<ShardMessage>,
+    // Taken once the thread has been joined
+    handle: Option<JoinHandle<Result<Ledger, PaymentError>>>,
+}
+
+pub struct ShardedLedger {
+    shards: Vec<Shard>,
+    // The shard each deposit/withdrawal/transfer tx_id was last sent to, if
+    // it's in a ledger at all it's in that one
+    tx_shards: HashMap<u32, usize>,
+    // The destination of each accepted transfer between clients on
+    // different shards, by tx_id
+    transfer_destinations: HashMap<u32, u16>,
+}
+
+impl ShardedLedger {
+    ///
+    /// Start the worker threads
+    ///
+    /// # Arguments
+    ///
+    /// * `ledger`: starting state (empty or loaded), it's split across the
+    ///   shards and its options are copied to each of them
+    /// * `shards`: number of worker threads
+    /// * `keep_going`: log a rejected transaction and carry on, otherwise the
+    ///   shard stops at its first rejection
+    ///
+    /// # Returns
+    ///
+    /// * `ShardedLedger`
+    ///
+    pub fn new(ledger: Ledger, shards: usize, keep_going: bool) -> Self {
+        let tx_shards = ledger
+            .by_transaction_id
+            .iter()
+            .map(|(&tx_id, record)| (tx_id, record.transaction.client_id as usize % shards))
+            .collect();
+        let transfer_destinations = ledger
+            .by_transaction_id
+            .iter()
+            .filter(|(_, record)| record.transaction.tx_type == TransactionType::Transfer)
+            .filter_map(|(&tx_id, record)| {
+                let destination = record.transaction.destination_id?;
+                let between_shards = destination as usize % shards
+                    != record.transaction.client_id as usize % shards;
+                between_shards.then_some((tx_id, destination))
+            })
+            .collect();
+        let shards = split_ledger(ledger, shards)
+            .into_iter()
+            .map(|mut ledger| {
+                let (sender, receiver) = sync_channel::<ShardMessage>(SHARD_QUEUE_DEPTH);
+                let handle = thread::spawn(move || {
+                    for message in receiver {
+                        let result = match message {
+                            ShardMessage::Transaction(true, transaction) => {
+                                ledger.process_admin_transaction(&transaction)
+                            }
+                            ShardMessage::Transaction(false, transaction) => {
+                                ledger.process_transaction(&transaction)
+                            }
+                            ShardMessage::ForeignTransaction { tx_id, client_id } => {
+                                ledger.add_foreign_transaction(tx_id, client_id);
+                                Ok(())
+                            }
+                            ShardMessage::FindTransaction { tx_id, reply } => {
+                                let owner = ledger
+                                    .by_transaction_id
+                                    .get(&tx_id)
+                                    .map(|record| record.transaction.client_id);
+                                // The reader is waiting for this
+                                let _ = reply.send(owner);
+                                Ok(())
+                            }
+                            ShardMessage::LendClient { client_id, reply } => {
+                                let _ = reply.send(ledger.by_client_id.remove(&client_id));
+                                Ok(())
+                            }
+                            ShardMessage::Borrowing {
+                                transaction,
+                                client_id,
+                                account,
+                                reply,
+                            } => {
+                                ledger.borrow_client(client_id, account);
+                                let result = ledger.process_transaction(&transaction);
+                                let _ = reply.send((result.is_ok(), ledger.return_client()));
+                                result
+                            }
+                            ShardMessage::ReturnClient(account) => {
+                                ledger.by_client_id.insert(account.client, account);
+                                Ok(())
+                            }
+                        };
+                        if let Err(err) = result {
+                            if !keep_going {
+                                return Err(err);
+                            }
+                            error!("Error: {}", err);
+                        }
+                    }
+                    Ok(ledger)
+                });
+                Shard {
+                    sender,
+                    handle: Some(handle),
+                }
+            })
+            .collect();
+        ShardedLedger {
+            shards,
+            tx_shards,
+            transfer_destinations,
+        }
+    }
+    ///
+    /// Queue a transaction from the feed on its client's shard
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `transaction`: Transaction to process
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), PaymentError>`: only an error if the shard has already
+    ///   stopped on an error (not keep_going), that's the error returned.
+    ///
+    pub fn process_transaction(&mut self, transaction: Transaction) -> Result<(), PaymentError> {
+        self.dispatch(false, transaction)
+    }
+    ///
+    /// Queue a transaction from the operator input on its client's shard
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `transaction`: Transaction to process
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), PaymentError>`: see `process_transaction`
+    ///
+    pub fn process_admin_transaction(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<(), PaymentError> {
+        self.dispatch(true, transaction)
+    }
+    ///
+    /// Wait for the shards to finish and merge them into one ledger
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Ledger, PaymentError>`: the merged ledger, or the error the
+    ///   first failed shard stopped on.
+    ///
+    pub fn finish(self) -> Result<Ledger, PaymentError> {
+        let mut ledgers = Vec::with_capacity(self.shards.len());
+        for shard in self.shards {
+            drop(shard.sender);
+            ledgers.push(join_shard(shard.handle)?);
+        }
+        Ok(merge_ledgers(ledgers))
+    }
+    //
+    // dispatch - hand a transaction to the shard that owns its client, once
+    // that shard knows about the tx_id if another shard has it, along with
+    // any client it needs from another shard.
+    //
+    fn dispatch(&mut self, admin: bool, transaction: Transaction) -> Result<(), PaymentError> {
+        let index = transaction.client_id as usize % self.shards.len();
+        if !admin {
+            self.check_tx_id(index, &transaction)?;
+            if let Some(client_id) = self.client_to_lend(index, &transaction) {
+                return self.dispatch_borrowing(index, client_id, transaction);
+            }
+        }
+        self.send(index, ShardMessage::Transaction(admin, transaction))
+    }
+    //
+    // client_to_lend - the client on another shard that a transaction going
+    // to shard `index` needs: the destination of a transfer, or of the
+    // transfer a dispute or resolve refers to.
+    //
+    fn client_to_lend(&self, index: usize, transaction: &Transaction) -> Option<u16> {
+        let client_id = match transaction.tx_type {
+            TransactionType::Transfer => transaction.destination_id?,
+            TransactionType::Dispute | TransactionType::Resolve => {
+                *self.transfer_destinations.get(&transaction.tx_id)?
+            }
+            _ => return None,
+        };
+        (client_id as usize % self.shards.len() != index).then_some(client_id)
+    }
+    //
+    // dispatch_borrowing - process a transaction on shard `index` with
+    // `client_id` lent by its own shard, and give the client back. The
+    // reader waits for each step, so neither shard gets anything else until
+    // the client is back where it belongs.
+    //
+    fn dispatch_borrowing(
+        &mut self,
+        index: usize,
+        client_id: u16,
+        transaction: Transaction,
+    ) -> Result<(), PaymentError> {
+        let lender = client_id as usize % self.shards.len();
+        let tx_id = transaction.tx_id;
+        let is_transfer = transaction.tx_type == TransactionType::Transfer;
+        let account = self.request(lender, |reply| ShardMessage::LendClient { client_id, reply })?;
+        let (accepted, account) = self.request(index, |reply| ShardMessage::Borrowing {
+            transaction,
+            client_id,
+            account,
+            reply,
+        })?;
+        if accepted && is_transfer {
+            self.transfer_destinations.insert(tx_id, client_id);
+        }
+        match account {
+            Some(account) => self.send(lender, ShardMessage::ReturnClient(account)),
+            None => Ok(()),
+        }
+    }
+    //
+    // check_tx_id - keep tx_shards up to date for a transaction going to
+    // shard `index`. If its tx_id went to another shard, ask that shard
+    // whose it is and pass it on. A deposit/withdrawal/transfer whose tx_id
+    // the other shard doesn't have could be accepted, so the tx_id moves
+    // to this shard.
+    //
+    fn check_tx_id(&mut self, index: usize, transaction: &Transaction) -> Result<(), PaymentError> {
+        let tx_id = transaction.tx_id;
+        let creates = matches!(
+            transaction.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawl | TransactionType::Transfer
+        );
+        match self.tx_shards.get(&tx_id) {
+            Some(&shard) if shard != index => {
+                let owner = self.request(shard, |reply| ShardMessage::FindTransaction {
+                    tx_id,
+                    reply,
+                })?;
+                match owner {
+                    Some(client_id) => {
+                        self.send(index, ShardMessage::ForeignTransaction { tx_id, client_id })?
+                    }
+                    None if creates => {
+                        self.tx_shards.insert(tx_id, index);
+                    }
+                    None => {}
+                }
+            }
+            None if creates => {
+                self.tx_shards.insert(tx_id, index);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    //
+    // send - queue a message for a shard. The send only fails if the shard's
+    // thread has stopped, in which case the reason it stopped is the error.
+    //
+    fn send(&mut self, index: usize, message: ShardMessage) -> Result<(), PaymentError> {
+        let shard = &mut self.shards[index];
+        if shard.sender.send(message).is_ok() {
+            return Ok(());
+        }
+        join_shard(shard.handle.take()).map(|_| ())
+    }
+    //
+    // request - send a shard a message that it replies to, and wait for the
+    // reply. It comes after everything already queued for the shard.
+    //
+    fn request<T>(
+        &mut self,
+        index: usize,
+        message: impl FnOnce(Sender<T>) -> ShardMessage,
+    ) -> Result<T, PaymentError> {
+        let (reply, receiver) = channel();
+        self.send(index, message(reply))?;
+        match receiver.recv() {
+            Ok(answer) => Ok(answer),
+            // It stopped before getting to the message
+            Err(_) => join_shard(self.shards[index].handle.take()).and_then(|_| {
+                Err(PaymentError::Io(io::Error::other(
+                    "Shard stopped without replying",
+                )))
+            }),
+        }
+    }
+}
+
+//
+// join_shard - wait for a shard's thread, a panic in it comes back as an
+// error rather than taking the reader down with it. A shard that has
+// already been joined had stopped on an error, which was returned then.
+//
+fn join_shard(
+    handle: Option<JoinHandle<Result<Ledger, PaymentError>>>,
+) -> Result<Ledger, PaymentError> {
+    let handle = handle
+        .ok_or_else(|| PaymentError::Io(io::Error::other("Shard stopped on an earlier error")))?;
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(PaymentError::Io(io::Error::other("Shard thread panicked"))))
+}
+
+///
+/// Split a ledger into per shard ledgers
+///
+/// Clients (and their transactions) go to shard `client_id % shards`. Every
+/// shard gets the ledger's options, run totals stay with the first.
+///
+/// # Arguments
+///
+/// * `ledger`: ledger to split
+/// * `shards`: number of shards, at least 1
+///
+/// # Returns
+///
+/// * `Vec<Ledger>`
+///
+pub fn split_ledger(ledger: Ledger, shards: usize) -> Vec<Ledger> {
+    let mut split: Vec<Ledger> = (0..shards)
+        .map(|index| {
+            let mut shard = Ledger::new();
+            shard.shard = Some((index, shards));
+            shard.strict_amounts = ledger.strict_amounts;
+            shard.dispute_policy = ledger.dispute_policy;
+            shard.reject_out_of_order = ledger.reject_out_of_order;
+            shard.dispute_window = ledger.dispute_window;
+            shard.dispute_deadline = ledger.dispute_deadline;
+            shard.last_seq_num = ledger.last_seq_num;
+            shard
+        })
+        .collect();
+    split[0].stats = ledger.stats;
+    for (client_id, account) in ledger.by_client_id {
+        split[client_id as usize % shards]
+            .by_client_id
+            .insert(client_id, account);
+    }
+    for (client_id, timestamp) in ledger.last_timestamps {
+        split[client_id as usize % shards]
+            .last_timestamps
+            .insert(client_id, timestamp);
+    }
+    for (tx_id, record) in ledger.by_transaction_id {
+        split[record.transaction.client_id as usize % shards]
+            .by_transaction_id
+            .insert(tx_id, record);
+    }
+    let mut entries: Vec<Vec<JournalEntry>> = vec![Vec::new(); shards];
+    for entry in ledger.journal.into_entries() {
+        entries[entry.client_id as usize % shards].push(entry);
+    }
+    for (shard, entries) in split.iter_mut().zip(entries) {
+        shard.journal = Journal::from_entries(entries);
+    }
+    split
+}
+
+///
+/// Merge per shard ledgers back into one
+///
+/// # Arguments
+///
+/// * `ledgers`: the shards, options are taken from the first. Their
+///   journals are put back together in seq_num order.
+///
+/// # Returns
+///
+/// * `Ledger`
+///
+pub fn merge_ledgers(ledgers: Vec<Ledger>) -> Ledger {
+    let mut merged = Ledger::new();
+    let mut entries = Vec::new();
+    if let Some(first) = ledgers.first() {
+        merged.strict_amounts = first.strict_amounts;
+        merged.dispute_policy = first.dispute_policy;
+        merged.reject_out_of_order = first.reject_out_of_order;
+        merged.dispute_window = first.dispute_window;
+        merged.dispute_deadline = first.dispute_deadline;
+    }
+    for ledger in ledgers {
+        merged.by_client_id.extend(ledger.by_client_id);
+        merged.by_transaction_id.extend(ledger.by_transaction_id);
+        merged.last_timestamps.extend(ledger.last_timestamps);
+        merged.stats.merge(&ledger.stats);
+        merged.last_seq_num = merged.last_seq_num.max(ledger.last_seq_num);
+        entries.extend(ledger.journal.into_entries());
+    }
+    // Back into the order the transactions were read in
+    entries.sort_by_key(JournalEntry::seq_num);
+    merged.journal = Journal::from_entries(entries);
+    merged
+}
@@ -0,0 +1,163 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::error::PaymentError;
+use crate::ledger::Ledger;
+use crate::transaction::TransactionType;
+
+//
+// Run summary
+//
+// What a run did, for operations. The reader counts the rows it read and
+// the ones that never made it to the ledger (parse errors), the ledger
+// counts what happened to each transaction it was given. RunSummary puts
+// the two together at the end of the run.
+//
+
+//
+// ReadSummary - returned by the readers in transaction.rs.
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReadSummary {
+    pub rows_read: u64,
+    // Rows rejected before reaching the ledger, by PaymentError::reason_code
+    pub rejected_by_reason: BTreeMap<String, u64>,
+}
+
+impl ReadSummary {
+    /// Count a row that was rejected before it reached the ledger.
+    pub fn reject(&mut self, err: &PaymentError) {
+        *self
+            .rejected_by_reason
+            .entry(err.reason_code().to_string())
+            .or_default() += 1;
+    }
+
+    /// Add the counts from another reader, e.g. the next input file.
+    pub fn merge(&mut self, other: &ReadSummary) {
+        self.rows_read += other.rows_read;
+        merge_counts(&mut self.rejected_by_reason, &other.rejected_by_reason);
+    }
+}
+
+//
+// LedgerStats - kept by the Ledger as transactions are processed.
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LedgerStats {
+    // Every transaction the ledger was given, by type
+    pub by_type: BTreeMap<String, u64>,
+    pub accepted: u64,
+    // Transactions the ledger refused, by PaymentError::reason_code
+    pub rejected_by_reason: BTreeMap<String, u64>,
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+    pub charged_back: Decimal,
+    pub transferred: Decimal,
+    pub clients_created: u64,
+    // Accounts that went from unlocked to locked (chargeback or freeze)
+    pub accounts_locked: u64,
+    // Disputes resolved for being open past the deadline
+    pub disputes_auto_resolved: u64,
+    // Withdrawals refused for lack of available funds
+    pub declined_withdrawals: u64,
+}
+
+impl LedgerStats {
+    /// Count a transaction and whether the ledger accepted it.
+    pub fn record(&mut self, tx_type: &TransactionType, result: &Result<(), PaymentError>) {
+        *self.by_type.entry(tx_type.as_str().to_string()).or_default() += 1;
+        match result {
+            Ok(()) => self.accepted += 1,
+            Err(err) => {
+                *self
+                    .rejected_by_reason
+                    .entry(err.reason_code().to_string())
+                    .or_default() += 1
+            }
+        }
+    }
+
+    /// Add the counts from another ledger, e.g. another shard.
+    pub fn merge(&mut self, other: &LedgerStats) {
+        merge_counts(&mut self.by_type, &other.by_type);
+        self.accepted += other.accepted;
+        merge_counts(&mut self.rejected_by_reason, &other.rejected_by_reason);
+        self.deposited += other.deposited;
+        self.withdrawn += other.withdrawn;
+        self.charged_back += other.charged_back;
+        self.transferred += other.transferred;
+        self.clients_created += other.clients_created;
+        self.accounts_locked += other.accounts_locked;
+        self.disputes_auto_resolved += other.disputes_auto_resolved;
+        self.declined_withdrawals += other.declined_withdrawals;
+    }
+}
+
+//
+// RunSummary - the report written at the end of a run.
+//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub rows_read: u64,
+    pub by_type: BTreeMap<String, u64>,
+    pub accepted: u64,
+    pub rejected: u64,
+    // Both parse errors and ledger rejections
+    pub rejected_by_reason: BTreeMap<String, u64>,
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+    pub charged_back: Decimal,
+    pub transferred: Decimal,
+    pub clients_created: u64,
+    pub accounts_locked: u64,
+    pub disputes_auto_resolved: u64,
+    pub declined_withdrawals: u64,
+    // Clients in the ledger at the end, and how many of them are locked
+    pub clients: u64,
+    pub locked_clients: u64,
+}
+
+impl RunSummary {
+    ///
+    /// Put together the summary of a run
+    ///
+    /// # Arguments
+    ///
+    /// * `read`: what the readers saw, over all the inputs
+    /// * `ledger`: the ledger at the end of the run
+    ///
+    /// # Returns
+    ///
+    /// * `RunSummary`
+    ///
+    pub fn new(read: &ReadSummary, ledger: &Ledger) -> Self {
+        let stats = &ledger.stats;
+        let mut rejected_by_reason = read.rejected_by_reason.clone();
+        merge_counts(&mut rejected_by_reason, &stats.rejected_by_reason);
+        RunSummary {
+            rows_read: read.rows_read,
+            by_type: stats.by_type.clone(),
+            accepted: stats.accepted,
+            rejected: rejected_by_reason.values().sum(),
+            rejected_by_reason,
+            deposited: stats.deposited,
+            withdrawn: stats.withdrawn,
+            charged_back: stats.charged_back,
+            transferred: stats.transferred,
+            clients_created: stats.clients_created,
+            accounts_locked: stats.accounts_locked,
+            disputes_auto_resolved: stats.disputes_auto_resolved,
+            declined_withdrawals: stats.declined_withdrawals,
+            clients: ledger.by_client_id.len() as u64,
+            locked_clients: ledger.by_client_id.values().filter(|a| a.locked).count() as u64,
+        }
+    }
+}
+
+fn merge_counts(into: &mut BTreeMap<String, u64>, from: &BTreeMap<String, u64>) {
+    for (key, count) in from {
+        *into.entry(key.clone()).or_default() += count;
+    }
+}
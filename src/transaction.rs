@@ -1,38 +1,159 @@
+use chrono::{DateTime, Utc};
 use core::fmt;
+use clap::ValueEnum;
 use csv::{Reader, Trim};
+use flate2::read::MultiGzDecoder;
 use log::error;
 use rust_decimal::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::fs::File;
-use std::io::Read;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
+use crate::error::PaymentError;
+use crate::rejects::RejectWriter;
+use crate::summary::ReadSummary;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TransactionType {
     Deposit,
     Withdrawl,
     Dispute,
     Resolve,
     Chargeback,
+    // From the client to another client, the destination
+    Transfer,
+    // Administrative, only accepted from the operator input (--admin)
+    Unlock,
+    Freeze,
+}
+
+impl TransactionType {
+    /// Is this an administrative transaction that only an operator can issue?
+    pub fn is_admin(&self) -> bool {
+        matches!(self, TransactionType::Unlock | TransactionType::Freeze)
+    }
+
+    /// The name used for this type in the input (and in reports).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawl => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Freeze => "freeze",
+        }
+    }
+
+    /// Does this type of transaction have to carry its own amount?
+    pub fn requires_amount(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Deposit | TransactionType::Withdrawl | TransactionType::Transfer
+        )
+    }
+}
+//
+// ReadSession - hands out seq_nums to the transactions read, in the order
+// they're read. One session is used for all the inputs of a run, so seq_nums
+// keep counting up across files. Each ledger (or test) has its own session,
+// so seq_nums don't depend on what else is running in the process.
+//
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadSession {
+    next_seq_num: u32,
+}
+
+impl ReadSession {
+    /// A session starting from seq_num 0.
+    pub fn new() -> Self {
+        ReadSession::default()
+    }
+
+    /// A session carrying on after `last_seq_num`, e.g. the
+    /// `Ledger::last_seq_num` of a restored snapshot. `None` starts from 0.
+    pub fn resume(last_seq_num: Option<u32>) -> Self {
+        ReadSession {
+            next_seq_num: last_seq_num.map_or(0, |seq_num| seq_num + 1),
+        }
+    }
+
+    /// Start again from seq_num 0.
+    pub fn reset(&mut self) {
+        self.next_seq_num = 0;
+    }
+
+    //
+    // next_seq_num - take the next seq_num
+    //
+    fn next_seq_num(&mut self) -> u32 {
+        let seq_num = self.next_seq_num;
+        self.next_seq_num += 1;
+        seq_num
+    }
 }
+
+//
+// InputFormat - what the transaction input files contain.
 //
-// Note: for this sample this is not necessary as we aren't threaded, but
-//       in a production payment processor where transactions would be read
-//       on multiple threads it would be.
-static CURRENT_SEQ: AtomicU32 = AtomicU32::new(0);
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum InputFormat {
+    /// CSV with a header row: type, client, tx, amount, timestamp, destination
+    #[default]
+    Csv,
+    /// One json object per line
+    Jsonl,
+}
+
+// Input name that means read stdin
+pub const STDIN_NAME: &str = "-";
+
+// First bytes of a gzip and a zstd stream
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
 
 // Define a struct to represent a transaction
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub seq_num: u32, // seq_num differs from tx_id in that it's when we received it
     pub tx_type: TransactionType,
     pub client_id: u16,
     pub tx_id: u32, // Transaction id as established by something outside of this
-    pub amount: rust_decimal::Decimal,
+    // Deposits/withdrawals/transfers always have an amount, for disputes,
+    // resolves and chargebacks it's optional as the ledger uses the
+    // original's amount.
+    pub amount: Option<rust_decimal::Decimal>,
+    // When it happened, if the input said. Read as RFC 3339 or epoch millis.
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+    // The client a transfer goes to, only transfers have one
+    #[serde(default)]
+    pub destination_id: Option<u16>,
+}
+
+//
+// EventTime - when something happened, by its seq_num and its timestamp if
+// the input had one. See DisputeWindow for how two of them are compared.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EventTime {
+    pub seq_num: u32,
+    pub timestamp: Option<DateTime<Utc>>,
 }
 
 impl Transaction {
+    /// When this transaction happened
+    pub fn event_time(&self) -> EventTime {
+        EventTime {
+            seq_num: self.seq_num,
+            timestamp: self.timestamp,
+        }
+    }
+
     /// Output a single transaction
     ///
     /// Write a single transaction as a json blob to an open file. This is
@@ -52,40 +173,145 @@ impl Transaction {
 
 impl fmt::Display for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: type:{}", self.seq_num, self.amount)
+        match self.amount {
+            Some(amount) => write!(f, "{}: type:{}", self.seq_num, amount),
+            None => write!(f, "{}: type:-", self.seq_num),
+        }
     }
 }
 
 /// Reads a CSV file from the given filename and processes each row using a provided function.
 ///
 /// This function handles opening the file and creating the CSV reader, then delegates
-/// to `process_csv_from_reader` for the actual processing.
+/// to `process_csv_from_reader` for the actual processing. Gzip and zstd
+/// compressed files are decompressed as they're read.
 ///
 /// # Arguments
 ///
-/// * `filename`: The path to the CSV file.
-/// * `process_func`: A closure that takes a `Transaction` and returns a `Result<(), Box<dyn Error>>`.
+/// * `filename`: The path to the CSV file, `-` reads stdin.
+/// * `format`: What's in the file, csv or json lines.
+/// * `session`: Gives the transactions their seq_nums.
+/// * `process_func`: A closure that takes a `Transaction` and returns a `Result<(), PaymentError>`.
 /// * `keep_going` : Keep going if you have an erroneous line true/false
+/// * `rejects` : Where to write rejected rows, if anywhere
 ///
 /// # Returns
 ///
-/// * `Result<(), Box<dyn Error>>`: Ok(()) if all transactions were processed successfully,
-///   otherwise an error indicating the first encountered issue.
+/// * `Result<ReadSummary, PaymentError>`: rows read and rows rejected before
+///   reaching `process_func` if all transactions were processed (or
+///   keep_going), otherwise an error indicating the first encountered issue.
 pub fn process_file<F>(
     filename: &str,
+    format: InputFormat,
+    session: &mut ReadSession,
     process_func: F,
     keep_going: bool,
-) -> Result<(), Box<dyn Error>>
+    mut rejects: Option<&mut RejectWriter>,
+) -> Result<ReadSummary, PaymentError>
 where
-    F: FnMut(Transaction) -> Result<(), Box<dyn Error>>,
+    F: FnMut(Transaction) -> Result<(), PaymentError>,
 {
-    let file = File::open(filename)?;
-    let rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .trim(Trim::All)
-        .from_reader(file);
+    if let Some(rejects) = rejects.as_deref_mut() {
+        rejects.set_source(filename);
+    }
+    let input = open_input(filename)?;
+    match format {
+        InputFormat::Csv => {
+            let rdr = csv::ReaderBuilder::new()
+                .has_headers(true)
+                .flexible(true)
+                .trim(Trim::All)
+                .from_reader(input);
+            process_csv_from_reader(rdr, session, process_func, keep_going, rejects)
+        }
+        InputFormat::Jsonl => {
+            process_jsonl_from_reader(input, session, process_func, keep_going, rejects)
+        }
+    }
+}
 
-    process_csv_from_reader(rdr, process_func, keep_going)
+//
+// open_input - open a file (or stdin) for reading, decompressing it on the
+// way if it's gzip or zstd. Compression is recognised by the magic bytes at
+// the start of the data, or failing that the extension (.gz or .zst).
+//
+fn open_input(filename: &str) -> Result<Box<dyn Read>, PaymentError> {
+    let input: Box<dyn Read> = if filename == STDIN_NAME {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(filename)?)
+    };
+    let mut input = BufReader::new(input);
+    let start = input.fill_buf()?;
+    let gzip = start.starts_with(GZIP_MAGIC);
+    let zstd = start.starts_with(ZSTD_MAGIC);
+    if gzip || (!zstd && filename.ends_with(".gz")) {
+        Ok(Box::new(MultiGzDecoder::new(input)))
+    } else if zstd || filename.ends_with(".zst") {
+        Ok(Box::new(zstd::Decoder::with_buffer(input)?))
+    } else {
+        Ok(Box::new(input))
+    }
+}
+
+/// Expand the inputs given on the command line into the files to read
+///
+/// `-` (stdin) and plain files are kept as they are. A directory becomes the
+/// files in it and a glob pattern (`*`, `?` or `[`) the paths it matches,
+/// both sorted by name. Order is otherwise kept, files are processed in the
+/// order they're returned.
+///
+/// # Arguments
+///
+/// * `names`: Inputs from the command line.
+///
+/// # Returns
+///
+/// * `Result<Vec<String>, PaymentError>`: the files, an error if a glob
+///   pattern is bad or matches nothing.
+pub fn expand_inputs(names: &[String]) -> Result<Vec<String>, PaymentError> {
+    let mut files = Vec::new();
+    for name in names {
+        if name == STDIN_NAME {
+            files.push(name.clone());
+        } else if Path::new(name).is_dir() {
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(name)? {
+                let path = entry?.path();
+                if path.is_file() {
+                    entries.push(path.to_string_lossy().into_owned());
+                }
+            }
+            entries.sort();
+            files.extend(entries);
+        } else if name.contains(['*', '?', '[']) {
+            let pattern = glob::glob(name).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Bad pattern {}: {}", name, e),
+                )
+            })?;
+            let mut matches = Vec::new();
+            for path in pattern {
+                let path = path.map_err(io::Error::from)?;
+                if path.is_file() {
+                    matches.push(path.to_string_lossy().into_owned());
+                }
+            }
+            if matches.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No files match {}", name),
+                )
+                .into());
+            }
+            matches.sort();
+            files.extend(matches);
+        } else {
+            files.push(name.clone());
+        }
+    }
+    Ok(files)
 }
 
 /// Translate that we have a transaction type from string to enum TransactionType
@@ -96,8 +322,8 @@ where
 ///
 /// # Returns
 ///
-/// * Result<TransactionType, Box<dyn Error>>
-pub fn translate_trx_type(trx_type: &str) -> Result<TransactionType, Box<dyn Error>> {
+/// * Result<TransactionType, PaymentError>
+pub fn translate_trx_type(trx_type: &str) -> Result<TransactionType, PaymentError> {
     let result = match trx_type {
         "withdrawal" => Ok(TransactionType::Withdrawl),
         "withdraw" => Ok(TransactionType::Withdrawl),
@@ -105,35 +331,45 @@ pub fn translate_trx_type(trx_type: &str) -> Result<TransactionType, Box<dyn Err
         "dispute" => Ok(TransactionType::Dispute),
         "resolve" => Ok(TransactionType::Resolve),
         "chargeback" => Ok(TransactionType::Chargeback),
-        _ => Err(format!("Unknown Tranaction {}", trx_type).into()),
+        "transfer" => Ok(TransactionType::Transfer),
+        "unlock" => Ok(TransactionType::Unlock),
+        "freeze" => Ok(TransactionType::Freeze),
+        _ => Err(PaymentError::parse(
+            0,
+            "type",
+            format!("Unknown Tranaction {}", trx_type),
+        )),
     };
     result
 }
 
 /// Reads a CSV from a string buffer and processes each row using a provided function.
 ///
+/// The buffer is read in a session of its own, seq_nums start from 0.
+///
 /// # Arguments
 ///
 /// * `buffer`: The string buffer containing CSV data.
-/// * `process_func`: A closure that takes a `Transaction` and returns a `Result<(), Box<dyn Error>>`.
+/// * `process_func`: A closure that takes a `Transaction` and returns a `Result<(), PaymentError>`.
 /// * `keep_going` : Keep going if you have an erroneous line true/false
 ///
 /// # Returns
 ///
-/// * Result<TransactionType, Box<dyn Error>>
+/// * Result<ReadSummary, PaymentError>
 pub fn process_csv_from_buffer<F>(
     buffer: &str,
     process_func: F,
     keep_going: bool,
-) -> Result<(), Box<dyn Error>>
+) -> Result<ReadSummary, PaymentError>
 where
-    F: FnMut(Transaction) -> Result<(), Box<dyn Error>>,
+    F: FnMut(Transaction) -> Result<(), PaymentError>,
 {
     let rdr = csv::ReaderBuilder::new()
         .has_headers(true)
+        .flexible(true)
         .from_reader(buffer.as_bytes());
 
-    process_csv_from_reader(rdr, process_func, keep_going)
+    process_csv_from_reader(rdr, &mut ReadSession::new(), process_func, keep_going, None)
 }
 
 /// Reads a CSV file and processes each row using a provided function.
@@ -141,103 +377,376 @@ where
 /// # Arguments
 ///
 /// * `rdr`: A `csv::Reader` instance from which to read records.
-/// * `process_func`: A closure that takes a `Transaction` and returns a `Result<(), Box<dyn Error>>`.
+/// * `session`: Gives the transactions their seq_nums.
+/// * `process_func`: A closure that takes a `Transaction` and returns a `Result<(), PaymentError>`.
 /// * `keep_going` : Keep going if we have an erroneous line. true/false
+/// * `rejects` : Where to write rejected rows, if anywhere
 ///
 /// # Returns
 ///
-/// * `Result<(), Box<dyn Error>>`: Ok(()) if all transactions were processed successfully,
-///   otherwise an error indicating the first encountered issue.
+/// * `Result<ReadSummary, PaymentError>`: rows read and rows rejected before
+///   reaching `process_func` if all transactions were processed (or
+///   keep_going), otherwise an error indicating the first encountered issue.
 ///
 pub fn process_csv_from_reader<R: Read, F>(
     mut rdr: Reader<R>,
+    session: &mut ReadSession,
     mut process_func: F,
     keep_going: bool,
-) -> Result<(), Box<dyn Error>>
+    mut rejects: Option<&mut RejectWriter>,
+) -> Result<ReadSummary, PaymentError>
 where
-    F: FnMut(Transaction) -> Result<(), Box<dyn Error>>,
+    F: FnMut(Transaction) -> Result<(), PaymentError>,
 {
+    let mut summary = ReadSummary::default();
     let mut cnt: u32 = 0;
     for result in rdr.records() {
-        cnt += 1;
-        let row_result = match result {
-            Ok(result) => {
-                let row_result = process_row(result, cnt);
-                let result = match row_result {
-                    Ok(row) =>  process_func(row),
-                    Err(e) => Err(format!("{}: line:{}", e, cnt).into()),
-                };
-                result
+        // The line in the file the record starts on, as the json lines
+        // reader counts them. Failing that, one on from the last record.
+        let position = match &result {
+            Ok(record) => record.position(),
+            Err(err) => err.position(),
+        };
+        cnt = position.map_or(cnt + 1, |position| position.line() as u32);
+        summary.rows_read += 1;
+        let (record, row_result) = match result {
+            Ok(record) => {
+                let row_result = run_row(&record, cnt, session, &mut process_func);
+                (Some(record), row_result)
+            }
+            Err(err) => (None, Err((None, PaymentError::Csv(err)))),
+        };
+        if let Err((seq_num, err)) = row_result {
+            // The ledger counts its own rejections
+            if seq_num.is_none() {
+                summary.reject(&err);
             }
-            Err(ref err) => Err(err.into()),
+            reject(err, cnt, seq_num, record.as_ref(), keep_going, rejects.as_deref_mut())?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Reads JSON Lines and processes each line using a provided function.
+///
+/// Each line is an object with the same fields as the csv columns, e.g.
+/// `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`. Numbers
+/// can be json numbers or strings, and the amount can be left out or null
+/// where the csv column could be empty. Blank lines are skipped.
+///
+/// # Arguments
+///
+/// * `rdr`: Where to read the lines from.
+/// * `session`: Gives the transactions their seq_nums.
+/// * `process_func`: A closure that takes a `Transaction` and returns a `Result<(), PaymentError>`.
+/// * `keep_going` : Keep going if we have an erroneous line. true/false
+/// * `rejects` : Where to write rejected rows, if anywhere
+///
+/// # Returns
+///
+/// * `Result<ReadSummary, PaymentError>`: rows read and rows rejected before
+///   reaching `process_func` if all transactions were processed (or
+///   keep_going), otherwise an error indicating the first encountered issue.
+///
+pub fn process_jsonl_from_reader<R: Read, F>(
+    rdr: R,
+    session: &mut ReadSession,
+    mut process_func: F,
+    keep_going: bool,
+    mut rejects: Option<&mut RejectWriter>,
+) -> Result<ReadSummary, PaymentError>
+where
+    F: FnMut(Transaction) -> Result<(), PaymentError>,
+{
+    let mut summary = ReadSummary::default();
+    let mut cnt: u32 = 0;
+    for line in BufReader::new(rdr).lines() {
+        cnt += 1;
+        let (record, line_result) = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => match jsonl_record(&line, cnt) {
+                Ok(record) => {
+                    summary.rows_read += 1;
+                    let line_result = run_row(&record, cnt, session, &mut process_func);
+                    (Some(record), line_result)
+                }
+                Err(err) => {
+                    summary.rows_read += 1;
+                    (jsonl_raw_record(&line), Err((None, err)))
+                }
+            },
+            Err(err) => (None, Err((None, PaymentError::Io(err)))),
         };
-        if let Err(err) = row_result {
-            if keep_going {
-                error!("Error: {}", err);
-                continue;
-            } else {
-                return Err(format!("process_func: {}", err).into());
+        if let Err((seq_num, err)) = line_result {
+            // The ledger counts its own rejections
+            if seq_num.is_none() {
+                summary.reject(&err);
             }
+            reject(err, cnt, seq_num, record.as_ref(), keep_going, rejects.as_deref_mut())?;
         }
     }
 
-    Ok(())
+    Ok(summary)
+}
+
+//
+// run_row - parse a record and hand the transaction to process_func. A
+//           failure comes back with the transaction's seq_num if it got as
+//           far as having one.
+//
+fn run_row<F>(
+    record: &csv::StringRecord,
+    cnt: u32,
+    session: &mut ReadSession,
+    process_func: &mut F,
+) -> Result<(), (Option<u32>, PaymentError)>
+where
+    F: FnMut(Transaction) -> Result<(), PaymentError>,
+{
+    let transaction = process_row(record, cnt, session).map_err(|err| (None, err))?;
+    let seq_num = transaction.seq_num;
+    process_func(transaction).map_err(|err| (Some(seq_num), err))
+}
+
+//
+// reject - deal with a row that failed. It's written to the rejects file (if
+//          there is one), then either logged so processing carries on or
+//          returned to stop it.
+//
+fn reject(
+    err: PaymentError,
+    cnt: u32,
+    seq_num: Option<u32>,
+    record: Option<&csv::StringRecord>,
+    keep_going: bool,
+    rejects: Option<&mut RejectWriter>,
+) -> Result<(), PaymentError> {
+    if let Some(rejects) = rejects {
+        rejects.write(cnt, seq_num, record, &err)?;
+    }
+    if keep_going {
+        error!("Error: {}", err);
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+//
+// jsonl_raw_record - the fields of a json line that jsonl_record refused,
+//                    as near as they can be got, for the rejects file.
+//
+fn jsonl_raw_record(line: &str) -> Option<csv::StringRecord> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    let object = value.as_object()?;
+    let mut record = csv::StringRecord::new();
+    for field in ["type", "client", "tx", "amount", "timestamp", "destination"] {
+        match object.get(field) {
+            Some(serde_json::Value::String(s)) => record.push_field(s),
+            None | Some(serde_json::Value::Null) => record.push_field(""),
+            Some(other) => record.push_field(&other.to_string()),
+        }
+    }
+    Some(record)
+}
+
+//
+// jsonl_record - turn a json line into the csv record it stands for, so it
+//                goes through exactly the same checks in process_row.
+//
+fn jsonl_record(line: &str, cnt: u32) -> Result<csv::StringRecord, PaymentError> {
+    let value: serde_json::Value = serde_json::from_str(line)
+        .map_err(|e| PaymentError::parse(cnt, "record", format!("Invalid json: {}", e)))?;
+    let object = value
+        .as_object()
+        .ok_or_else(|| PaymentError::parse(cnt, "record", "Expected a json object"))?;
+
+    let mut record = csv::StringRecord::new();
+    for field in ["type", "client", "tx", "amount", "timestamp", "destination"] {
+        let column = match object.get(field) {
+            Some(serde_json::Value::String(s)) => s.trim().to_string(),
+            Some(serde_json::Value::Number(n)) => n.to_string(),
+            None | Some(serde_json::Value::Null)
+                if matches!(field, "amount" | "timestamp" | "destination") =>
+            {
+                String::new()
+            }
+            None => {
+                return Err(PaymentError::parse(
+                    cnt,
+                    field,
+                    format!("Missing {} field", field),
+                ))
+            }
+            Some(other) => {
+                return Err(PaymentError::parse(
+                    cnt,
+                    field,
+                    format!("Expected a string or number, got {}", other),
+                ))
+            }
+        };
+        record.push_field(&column);
+    }
+    Ok(record)
 }
 
 //
 // process_row - process a single row. Broken out from the above function so that
 //               there is finer grain control over continue/stop functionality.
 //
-fn process_row(record: csv::StringRecord, cnt: u32) -> Result<Transaction, Box<dyn Error>> {
-    // Ensure the record has the expected number of fields
-    if record.len() != 4 {
-        return Err(format!(
-            "Invalid record format: expected 4 fields, got {}. Line: {}",
-            record.len(),
-            cnt
-        )
-        .into());
+fn process_row(
+    record: &csv::StringRecord,
+    cnt: u32,
+    session: &mut ReadSession,
+) -> Result<Transaction, PaymentError> {
+    // Ensure the record has the expected number of fields, the amount can be
+    // left off entirely for the types that don't need one, the timestamp is
+    // optional and only transfers have a destination.
+    if !(3..=6).contains(&record.len()) {
+        return Err(PaymentError::parse(
+            cnt,
+            "record",
+            format!(
+                "Invalid record format: expected 3 to 6 fields, got {}",
+                record.len()
+            ),
+        ));
     }
 
-    let tx_type = record.get(0).ok_or("Missing type field")?.to_string();
-    let tx_type = translate_trx_type(&tx_type)?;
-    let client_id_str = record.get(1).ok_or("Missing client field")?;
-    let tx_id_str = record.get(2).ok_or("Missing tx field")?;
-    let amount_str = record.get(3).ok_or("Missing amount field")?;
+    let tx_type = record
+        .get(0)
+        .ok_or_else(|| PaymentError::parse(cnt, "type", "Missing type field"))?;
+    let tx_type = translate_trx_type(tx_type).map_err(|e| e.at_line(cnt))?;
+    let client_id_str = record
+        .get(1)
+        .ok_or_else(|| PaymentError::parse(cnt, "client", "Missing client field"))?;
+    let tx_id_str = record
+        .get(2)
+        .ok_or_else(|| PaymentError::parse(cnt, "tx", "Missing tx field"))?;
+    let amount_str = record.get(3).unwrap_or("");
+    let timestamp_str = record.get(4).unwrap_or("");
+    let destination_str = record.get(5).unwrap_or("");
 
     // Parse client_id
-    let client_id = client_id_str
-        .parse::<u16>()
-        .map_err(|e| format!("Failed to parse client ID '{}': {}", client_id_str, e))?;
+    let client_id = client_id_str.parse::<u16>().map_err(|e| {
+        PaymentError::parse(
+            cnt,
+            "client",
+            format!("Failed to parse client ID '{}': {}", client_id_str, e),
+        )
+    })?;
 
     // Parse tx_id
-    let tx_id = tx_id_str
-        .parse::<u32>()
-        .map_err(|e| format!("Failed to parse transaction ID '{}': {}", tx_id_str, e))?;
+    let tx_id = tx_id_str.parse::<u32>().map_err(|e| {
+        PaymentError::parse(
+            cnt,
+            "tx",
+            format!("Failed to parse transaction ID '{}': {}", tx_id_str, e),
+        )
+    })?;
 
-    // Parse amount using rust_decimal for precise decimal handling
-    let amount = rust_decimal::Decimal::from_str(amount_str)
-        .map_err(|e| format!("Failed to parse amount '{}': {}", amount_str, e))?;
+    // Only deposits and withdrawals have to carry an amount
+    let amount = if amount_str.is_empty() {
+        if tx_type.requires_amount() {
+            return Err(PaymentError::parse(cnt, "amount", "Missing amount field"));
+        }
+        None
+    } else {
+        Some(parse_amount(amount_str, cnt)?)
+    };
 
-    // Amount's should be at most 4 places
-    let rounded_amount = amount.round_dp(4);
+    let timestamp = if timestamp_str.is_empty() {
+        None
+    } else {
+        Some(parse_timestamp(timestamp_str, cnt)?)
+    };
 
-    if amount != rounded_amount {
-        return Err(format!("Amount not formatted correctly {}", amount_str).into());
-    }
+    // A transfer has to say who it's to, nothing else can
+    let destination_id = if destination_str.is_empty() {
+        if tx_type == TransactionType::Transfer {
+            return Err(PaymentError::parse(
+                cnt,
+                "destination",
+                "Missing destination field",
+            ));
+        }
+        None
+    } else if tx_type != TransactionType::Transfer {
+        return Err(PaymentError::parse(
+            cnt,
+            "destination",
+            format!("Only a transfer has a destination, not a {}", tx_type.as_str()),
+        ));
+    } else {
+        Some(destination_str.parse::<u16>().map_err(|e| {
+            PaymentError::parse(
+                cnt,
+                "destination",
+                format!("Failed to parse destination '{}': {}", destination_str, e),
+            )
+        })?)
+    };
 
-    // Note: atomic here is a bit of an overkill in this example, but
-    // would be needed in production/multithreaded version.
-    let seq_num = CURRENT_SEQ.fetch_add(1, Ordering::Relaxed);
+    let seq_num = session.next_seq_num();
     let transaction = Transaction {
         seq_num,
         tx_type,
         client_id,
         tx_id,
         amount,
+        timestamp,
+        destination_id,
     };
     Ok(transaction)
 }
+//
+// parse_amount - parse an amount column, making sure it has at most 4
+//                decimal places.
+//
+fn parse_amount(amount_str: &str, cnt: u32) -> Result<Decimal, PaymentError> {
+    // Parse amount using rust_decimal for precise decimal handling
+    let amount = rust_decimal::Decimal::from_str(amount_str).map_err(|e| {
+        PaymentError::parse(
+            cnt,
+            "amount",
+            format!("Failed to parse amount '{}': {}", amount_str, e),
+        )
+    })?;
+
+    // Amount's should be at most 4 places
+    let rounded_amount = amount.round_dp(4);
+
+    if amount != rounded_amount {
+        return Err(PaymentError::parse(
+            cnt,
+            "amount",
+            format!("Amount not formatted correctly {}", amount_str),
+        ));
+    }
+    Ok(amount)
+}
+//
+// parse_timestamp - parse a timestamp column, either RFC 3339 (any offset,
+//                   kept as UTC) or milliseconds since the epoch.
+//
+fn parse_timestamp(timestamp_str: &str, cnt: u32) -> Result<DateTime<Utc>, PaymentError> {
+    let parsed = match timestamp_str.parse::<i64>() {
+        Ok(millis) => DateTime::from_timestamp_millis(millis)
+            .ok_or_else(|| format!("out of range {}", millis)),
+        Err(_) => DateTime::parse_from_rfc3339(timestamp_str)
+            .map(|timestamp| timestamp.with_timezone(&Utc))
+            .map_err(|e| e.to_string()),
+    };
+    parsed.map_err(|e| {
+        PaymentError::parse(
+            cnt,
+            "timestamp",
+            format!("Failed to parse timestamp '{}': {}", timestamp_str, e),
+        )
+    })
+}
 // ////////////////////////////////////////////////////////////////////
 // Unit Tests
 // ////////////////////////////////////////////////////////////////////
@@ -260,7 +769,7 @@ mod tests {
         let csv_content =
             "type, client, tx, amount\ndeposit,101,1000001,123.4567\nwithdraw,202,1000002,78.90\ndeposit,101,1000003,50.00";
         let mut processed_transactions = Vec::new();
-        let process_func = |tx: Transaction| -> Result<(), Box<dyn Error>> {
+        let process_func = |tx: Transaction| -> Result<(), PaymentError> {
             processed_transactions.push(tx.clone());
             Ok(())
         };
@@ -273,7 +782,7 @@ mod tests {
         assert_eq!(processed_transactions[0].tx_id, 1000001);
         assert_eq!(
             processed_transactions[0].amount,
-            rust_decimal::Decimal::from_str("123.4567")?
+            Some(rust_decimal::Decimal::from_str("123.4567")?)
         );
 
         assert_eq!(
@@ -284,7 +793,7 @@ mod tests {
         assert_eq!(processed_transactions[1].tx_id, 1000002);
         assert_eq!(
             processed_transactions[1].amount,
-            rust_decimal::Decimal::from_str("78.90")?
+            Some(rust_decimal::Decimal::from_str("78.90")?)
         );
 
         assert_eq!(processed_transactions[2].tx_type, TransactionType::Deposit);
@@ -292,7 +801,7 @@ mod tests {
         assert_eq!(processed_transactions[2].tx_id, 1000003);
         assert_eq!(
             processed_transactions[2].amount,
-            rust_decimal::Decimal::from_str("50.00")?
+            Some(rust_decimal::Decimal::from_str("50.00")?)
         );
 
         Ok(())
@@ -300,7 +809,14 @@ mod tests {
 
     #[test]
     fn test_process_file_file_not_found() {
-        let result = process_file("non_existent_file.csv", |_| Ok(()), false);
+        let result = process_file(
+            "non_existent_file.csv",
+            InputFormat::Csv,
+            &mut ReadSession::new(),
+            |_| Ok(()),
+            false,
+            None,
+        );
         assert!(result.is_err());
         assert!(result
             .unwrap_err()
@@ -312,7 +828,10 @@ mod tests {
     fn test_process_invalid_client_id() -> Result<(), Box<dyn Error>> {
         let csv_content = "type, client, tx, amount\ndeposit,abc,1000001,100.00";
         let result = process_csv_from_buffer(csv_content, |_| Ok(()), false);
-        assert!(result.is_err());
+        assert!(matches!(
+            result,
+            Err(PaymentError::ParseError { line: 2, ref field, .. }) if field == "client"
+        ));
         assert!(result
             .unwrap_err()
             .to_string()
@@ -340,6 +859,54 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_admin_transaction_empty_amount() -> Result<(), Box<dyn Error>> {
+        let csv_content = "type, client, tx, amount\nfreeze,7,900,\nunlock,7,901,";
+        let mut processed_transactions = Vec::new();
+        process_csv_from_buffer(
+            csv_content,
+            |tx| {
+                processed_transactions.push(tx);
+                Ok(())
+            },
+            false,
+        )?;
+        assert_eq!(processed_transactions.len(), 2);
+        assert_eq!(processed_transactions[0].tx_type, TransactionType::Freeze);
+        assert_eq!(processed_transactions[1].tx_type, TransactionType::Unlock);
+        assert_eq!(processed_transactions[1].amount, None);
+
+        // Deposits still need an amount
+        let csv_content = "type, client, tx, amount\ndeposit,7,902,";
+        let result = process_csv_from_buffer(csv_content, |_| Ok(()), false);
+        assert!(matches!(
+            result,
+            Err(PaymentError::ParseError { ref field, .. }) if field == "amount"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_family_without_amount() -> Result<(), Box<dyn Error>> {
+        // Both an empty amount and no amount column at all
+        let csv_content =
+            "type, client, tx, amount\ndispute,1,1,\nresolve,1,1\nchargeback,1,1,1.5";
+        let mut processed_transactions = Vec::new();
+        process_csv_from_buffer(
+            csv_content,
+            |tx| {
+                processed_transactions.push(tx);
+                Ok(())
+            },
+            false,
+        )?;
+        assert_eq!(processed_transactions.len(), 3);
+        assert_eq!(processed_transactions[0].amount, None);
+        assert_eq!(processed_transactions[1].amount, None);
+        assert_eq!(processed_transactions[2].amount, Some(Decimal::new(15, 1)));
+        Ok(())
+    }
+
     #[test]
     fn test_process_invalid_tx_id() -> Result<(), Box<dyn Error>> {
         let csv_content = "type, client, tx, amount\ndeposit,101,xyz,100.00";
@@ -368,12 +935,17 @@ mod tests {
     fn test_process_invalid_record_format() -> Result<(), Box<dyn Error>> {
         let csv_content = "type, client, tx, amount\ndeposit,101,1000001"; // Missing amount field
         let result = process_csv_from_buffer(csv_content, |_| Ok(()), false);
-        assert!(result.is_err());
-        // Note: contains() isn't very resilient, should/would improve
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("CSV error: record 1 (line: 2, byte: 25): found record with 3 fields, but the previous record has 4 fields"));
+        assert!(matches!(
+            result,
+            Err(PaymentError::ParseError { line: 2, ref field, .. }) if field == "amount"
+        ));
+
+        let csv_content = "type, client, tx, amount\ndeposit,101"; // Missing tx and amount
+        let result = process_csv_from_buffer(csv_content, |_| Ok(()), false);
+        assert!(matches!(
+            result,
+            Err(PaymentError::ParseError { ref field, .. }) if field == "record"
+        ));
         Ok(())
     }
 
@@ -387,15 +959,23 @@ mod tests {
             .ok_or("Failed to get temp file path")?;
 
         let mut call_count = 0;
-        let process_func = |_tx: Transaction| -> Result<(), Box<dyn Error>> {
+        let process_func = |tx: Transaction| -> Result<(), PaymentError> {
             call_count += 1;
-            Err("Error during processing".into())
+            Err(PaymentError::DuplicateTransaction(tx.tx_id))
         };
 
-        let result = process_file(filename, process_func, false);
-        assert!(result.is_err());
-        let str = result.unwrap_err().to_string();
-        assert_eq!(str, "process_func: Error during processing");
+        let result = process_file(
+            filename,
+            InputFormat::Csv,
+            &mut ReadSession::new(),
+            process_func,
+            false,
+            None,
+        );
+        assert!(matches!(
+            result,
+            Err(PaymentError::DuplicateTransaction(1000001))
+        ));
         assert_eq!(call_count, 1); // Should only call the function once before erroring
         Ok(())
     }
@@ -406,7 +986,7 @@ mod tests {
             "type, client, tx, amount\ndeposit,101,1000001,123.4567\nwithdraw,202,1000002,78.90";
 
         let mut processed_transactions = Vec::new();
-        let process_func = |tx: Transaction| -> Result<(), Box<dyn Error>> {
+        let process_func = |tx: Transaction| -> Result<(), PaymentError> {
             processed_transactions.push(tx);
             Ok(())
         };
@@ -419,13 +999,327 @@ mod tests {
         assert_eq!(processed_transactions[0].tx_id, 1000001);
         assert_eq!(
             processed_transactions[0].amount,
-            rust_decimal::Decimal::from_str("123.4567")?
+            Some(rust_decimal::Decimal::from_str("123.4567")?)
         );
         assert_eq!(
             processed_transactions[1].amount,
-            rust_decimal::Decimal::from_str("78.90")?
+            Some(rust_decimal::Decimal::from_str("78.90")?)
         );
 
         Ok(())
     }
+
+    #[test]
+    fn test_expand_inputs() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::TempDir::new()?;
+        for name in ["b.csv", "a.csv", "c.txt"] {
+            fs::write(dir.path().join(name), "type, client, tx, amount\n")?;
+        }
+        fs::create_dir(dir.path().join("sub"))?;
+        let root = dir.path().to_str().unwrap();
+        let path = |name: &str| format!("{}/{}", root, name);
+
+        // stdin and plain files are passed through in order
+        let names = vec![path("c.txt"), STDIN_NAME.to_string(), path("a.csv")];
+        assert_eq!(expand_inputs(&names)?, names);
+
+        // a directory is its files, sorted, without subdirectories
+        assert_eq!(
+            expand_inputs(&[root.to_string()])?,
+            vec![path("a.csv"), path("b.csv"), path("c.txt")]
+        );
+
+        // a glob is the files it matches, sorted
+        assert_eq!(
+            expand_inputs(&[path("*.csv"), path("c.txt")])?,
+            vec![path("a.csv"), path("b.csv"), path("c.txt")]
+        );
+
+        // a glob that matches nothing is an error
+        assert!(matches!(
+            expand_inputs(&[path("*.zip")]),
+            Err(PaymentError::Io(_))
+        ));
+        Ok(())
+    }
+
+    // Read every transaction from a file
+    fn read_all(filename: &str) -> Result<Vec<Transaction>, PaymentError> {
+        let mut transactions = Vec::new();
+        process_file(
+            filename,
+            InputFormat::Csv,
+            &mut ReadSession::new(),
+            |tx: Transaction| -> Result<(), PaymentError> {
+                transactions.push(tx);
+                Ok(())
+            },
+            false,
+            None,
+        )?;
+        Ok(transactions)
+    }
+
+    #[test]
+    fn test_process_compressed_files() -> Result<(), Box<dyn Error>> {
+        let plain = read_all("sample_data/sample1.txt")?;
+        assert_eq!(plain.len(), 5);
+        for filename in ["sample_data/sample1.txt.gz", "sample_data/sample1.txt.zst"] {
+            let transactions = read_all(filename)?;
+            assert_eq!(transactions.len(), plain.len());
+            for (tx, expected) in transactions.iter().zip(&plain) {
+                assert_eq!(tx.tx_type, expected.tx_type);
+                assert_eq!(tx.client_id, expected.client_id);
+                assert_eq!(tx.tx_id, expected.tx_id);
+                assert_eq!(tx.amount, expected.amount);
+            }
+
+            // found by the magic bytes without the extension
+            let renamed = create_temp_csv("")?;
+            fs::copy(filename, renamed.path())?;
+            assert_eq!(read_all(renamed.path().to_str().unwrap())?.len(), plain.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_corrupt_compressed_file() -> Result<(), Box<dyn Error>> {
+        let mut data = fs::read("sample_data/sample1.txt.gz")?;
+        data.truncate(data.len() / 2);
+        let file = tempfile::Builder::new().suffix(".gz").tempfile()?;
+        fs::write(file.path(), data)?;
+        let result = read_all(file.path().to_str().unwrap());
+        assert!(matches!(result, Err(PaymentError::Csv(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_jsonl_matches_csv() -> Result<(), Box<dyn Error>> {
+        let plain = read_all("sample_data/sample1.txt")?;
+        let mut transactions = Vec::new();
+        process_file(
+            "sample_data/sample1.jsonl",
+            InputFormat::Jsonl,
+            &mut ReadSession::new(),
+            |tx: Transaction| -> Result<(), PaymentError> {
+                transactions.push(tx);
+                Ok(())
+            },
+            false,
+            None,
+        )?;
+        assert_eq!(transactions.len(), plain.len());
+        for (tx, expected) in transactions.iter().zip(&plain) {
+            assert_eq!(tx.tx_type, expected.tx_type);
+            assert_eq!(tx.client_id, expected.client_id);
+            assert_eq!(tx.tx_id, expected.tx_id);
+            assert_eq!(tx.amount, expected.amount);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_jsonl_fields() -> Result<(), Box<dyn Error>> {
+        let jsonl = concat!(
+            "{\"type\": \"deposit\", \"client\": \"7\", \"tx\": 1, \"amount\": 12.25}\n",
+            "\n",
+            "{\"type\": \"dispute\", \"client\": 7, \"tx\": 1}\n",
+            "{\"type\": \"resolve\", \"client\": 7, \"tx\": 1, \"amount\": null}\n",
+        );
+        let mut transactions = Vec::new();
+        process_jsonl_from_reader(
+            jsonl.as_bytes(),
+            &mut ReadSession::new(),
+            |tx: Transaction| -> Result<(), PaymentError> {
+                transactions.push(tx);
+                Ok(())
+            },
+            false,
+            None,
+        )?;
+        assert_eq!(transactions.len(), 3);
+        assert_eq!(transactions[0].client_id, 7);
+        assert_eq!(transactions[0].amount, Some(Decimal::from_str("12.25")?));
+        assert_eq!(transactions[1].tx_type, TransactionType::Dispute);
+        assert_eq!(transactions[1].amount, None);
+        assert_eq!(transactions[2].amount, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_jsonl_errors() {
+        let cases = [
+            // too many decimal places, as a number and as a string
+            ("{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1.23456}", "amount"),
+            ("{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1.23456\"}", "amount"),
+            ("{\"type\": \"deposit\", \"client\": 1, \"tx\": 1}", "amount"),
+            ("{\"type\": \"deposit\", \"tx\": 1, \"amount\": \"1\"}", "client"),
+            ("{\"type\": \"deposit\", \"client\": -1, \"tx\": 1, \"amount\": \"1\"}", "client"),
+            ("{\"type\": \"bogus\", \"client\": 1, \"tx\": 1, \"amount\": \"1\"}", "type"),
+            ("{\"type\": \"deposit\", \"client\": 1, \"tx\": [1], \"amount\": \"1\"}", "tx"),
+            ("[\"deposit\", 1, 1, \"1\"]", "record"),
+            ("{\"type\": \"deposit\",", "record"),
+        ];
+        for (bad_line, bad_field) in cases {
+            // the bad line is the 3rd, after a good one and a blank one
+            let jsonl = format!(
+                "{{\"type\": \"deposit\", \"client\": 1, \"tx\": 9, \"amount\": \"1\"}}\n\n{}\n",
+                bad_line
+            );
+            let result = process_jsonl_from_reader(
+                jsonl.as_bytes(),
+                &mut ReadSession::new(),
+                |_| Ok(()),
+                false,
+                None,
+            );
+            match result {
+                Err(PaymentError::ParseError { line, field, .. }) => {
+                    assert_eq!(line, 3, "{}", bad_line);
+                    assert_eq!(field, bad_field, "{}", bad_line);
+                }
+                other => panic!("{}: expected a parse error, got {:?}", bad_line, other),
+            }
+        }
+    }
+
+    //
+    // * Timestamps as RFC 3339 (any offset) or epoch millis, from csv and
+    //   jsonl, all come out in UTC
+    // * The column can be empty or left off
+    // * Anything else is a parse error on the timestamp field
+    //
+    #[test]
+    fn test_process_timestamps() -> Result<(), Box<dyn Error>> {
+        let csv_content = "type,client,tx,amount,timestamp\n\
+                           deposit,1,1,1.0,2024-03-01T12:00:00Z\n\
+                           deposit,1,2,1.0,2024-03-01T14:00:00+02:00\n\
+                           deposit,1,3,1.0,1709294400000\n\
+                           deposit,1,4,1.0,\n\
+                           deposit,1,5,1.0\n";
+        let mut timestamps = Vec::new();
+        process_csv_from_buffer(
+            csv_content,
+            |tx: Transaction| -> Result<(), PaymentError> {
+                timestamps.push(tx.timestamp);
+                Ok(())
+            },
+            false,
+        )?;
+        let noon = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")?.with_timezone(&Utc);
+        assert_eq!(timestamps, vec![Some(noon), Some(noon), Some(noon), None, None]);
+
+        let jsonl = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1, \"timestamp\": 1709294400000}\n";
+        let mut timestamps = Vec::new();
+        process_jsonl_from_reader(
+            jsonl.as_bytes(),
+            &mut ReadSession::new(),
+            |tx: Transaction| -> Result<(), PaymentError> {
+                timestamps.push(tx.timestamp);
+                Ok(())
+            },
+            false,
+            None,
+        )?;
+        assert_eq!(timestamps, vec![Some(noon)]);
+
+        for bad in ["yesterday", "2024-03-01 12:00:00", "9223372036854775807"] {
+            let csv_content = format!("type,client,tx,amount,timestamp\ndeposit,1,1,1.0,{}\n", bad);
+            match process_csv_from_buffer(&csv_content, |_| Ok(()), false) {
+                Err(PaymentError::ParseError { field, .. }) => assert_eq!(field, "timestamp"),
+                other => panic!("{}: expected a parse error, got {:?}", bad, other),
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // * A transfer's destination is the column after the timestamp, from
+    //   csv and jsonl
+    // * A transfer without one, or anything else with one, is a parse error
+    //   on the destination field
+    //
+    #[test]
+    fn test_process_transfers() -> Result<(), Box<dyn Error>> {
+        let csv_content = "type,client,tx,amount,timestamp,destination\n\
+                           transfer,1,1,1.5,,2\n\
+                           deposit,1,2,1.0,,\n";
+        let mut transfers = Vec::new();
+        process_csv_from_buffer(
+            csv_content,
+            |tx: Transaction| -> Result<(), PaymentError> {
+                transfers.push((tx.tx_type, tx.amount, tx.destination_id));
+                Ok(())
+            },
+            false,
+        )?;
+        assert_eq!(
+            transfers,
+            vec![
+                (TransactionType::Transfer, Some(Decimal::new(15, 1)), Some(2)),
+                (TransactionType::Deposit, Some(Decimal::ONE), None),
+            ]
+        );
+
+        let jsonl = "{\"type\": \"transfer\", \"client\": 1, \"tx\": 1, \"amount\": \"1.5\", \"destination\": 2}\n";
+        let mut destinations = Vec::new();
+        process_jsonl_from_reader(
+            jsonl.as_bytes(),
+            &mut ReadSession::new(),
+            |tx: Transaction| -> Result<(), PaymentError> {
+                destinations.push(tx.destination_id);
+                Ok(())
+            },
+            false,
+            None,
+        )?;
+        assert_eq!(destinations, vec![Some(2)]);
+
+        for bad in [
+            "transfer,1,1,1.0",
+            "transfer,1,1,1.0,,",
+            "transfer,1,1,1.0,,Frog",
+            "deposit,1,1,1.0,,2",
+        ] {
+            let csv_content = format!("type,client,tx,amount,timestamp,destination\n{}\n", bad);
+            match process_csv_from_buffer(&csv_content, |_| Ok(()), false) {
+                Err(PaymentError::ParseError { field, .. }) => assert_eq!(field, "destination"),
+                other => panic!("{}: expected a parse error, got {:?}", bad, other),
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // * A session numbers transactions from 0, across everything read in it
+    // * Resetting starts again from 0, resuming carries on after a seq_num
+    //
+    #[test]
+    fn test_read_session_seq_nums() -> Result<(), Box<dyn Error>> {
+        let csv_content = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\n";
+        let read_seq_nums = |session: &mut ReadSession| -> Result<Vec<u32>, PaymentError> {
+            let mut seq_nums = Vec::new();
+            let rdr = csv::ReaderBuilder::new().from_reader(csv_content.as_bytes());
+            process_csv_from_reader(
+                rdr,
+                session,
+                |tx: Transaction| -> Result<(), PaymentError> {
+                    seq_nums.push(tx.seq_num);
+                    Ok(())
+                },
+                false,
+                None,
+            )?;
+            Ok(seq_nums)
+        };
+
+        let mut session = ReadSession::new();
+        assert_eq!(read_seq_nums(&mut session)?, vec![0, 1]);
+        assert_eq!(read_seq_nums(&mut session)?, vec![2, 3]);
+        session.reset();
+        assert_eq!(read_seq_nums(&mut session)?, vec![0, 1]);
+        assert_eq!(read_seq_nums(&mut ReadSession::resume(Some(41)))?, vec![42, 43]);
+        assert_eq!(read_seq_nums(&mut ReadSession::resume(None))?, vec![0, 1]);
+        Ok(())
+    }
 }
@@ -0,0 +1,242 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+
+use crate::account::AccountStatus;
+use crate::error::PaymentError;
+use crate::ledger::{DisputeState, Ledger};
+use crate::transaction::Transaction;
+
+//
+// Write-ahead log
+//
+// Every transaction the ledger processes is appended to the log, accepted
+// ones before the ledger is changed. The log is plain text, one record per
+// line:
+//
+//   <crc32 of the json, 8 hex digits> <WalRecord as json>\n
+//
+// A record only counts if the whole line made it to disk, the checksum
+// matches and the json parses. Reading stops at the first record that
+// doesn't, which is what a crash part way through a write leaves behind.
+//
+
+//
+// WalOutcome - what happened to a transaction. For accepted transactions
+// this is the client's account afterwards (and where the referenced
+// transaction moved to), so a replay can be checked against it.
+//
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WalOutcome {
+    Accepted {
+        account: AccountStatus,
+        dispute_state: Option<DisputeState>,
+    },
+    Rejected {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub lsn: u64, // log sequence number, starts at 1 and goes up by 1 per record
+    pub admin: bool, // came from the operator input rather than the feed
+    pub transaction: Transaction,
+    pub outcome: WalOutcome,
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    next_lsn: u64,
+}
+
+impl Wal {
+    ///
+    /// Open a write-ahead log for appending
+    ///
+    /// The log is created if it doesn't exist. If it ends in a partial or
+    /// corrupt record (a crash mid-write) that tail is cut off so new records
+    /// follow the last good one.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename`: log file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Wal, PaymentError>`
+    ///
+    pub fn open(filename: &str) -> Result<Wal, PaymentError> {
+        let (records, valid_len) = read_wal(filename)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)?;
+        if file.metadata()?.len() != valid_len {
+            warn!(
+                "Dropping torn tail of write-ahead log {} after byte {}",
+                filename, valid_len
+            );
+            file.set_len(valid_len)?;
+        }
+        let next_lsn = records.last().map_or(1, |record| record.lsn + 1);
+        Ok(Wal { file, next_lsn })
+    }
+    ///
+    /// Append a record to the log
+    ///
+    /// The record is synced to disk before this returns.
+    ///
+    /// # Arguments
+    ///
+    /// * `self`: Self
+    /// * `admin`: true if the transaction came from the operator input
+    /// * `transaction`: the transaction
+    /// * `outcome`: what happened to it
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64, PaymentError>`: the log sequence number of the record
+    ///
+    pub fn append(
+        &mut self,
+        admin: bool,
+        transaction: &Transaction,
+        outcome: WalOutcome,
+    ) -> Result<u64, PaymentError> {
+        let record = WalRecord {
+            lsn: self.next_lsn,
+            admin,
+            transaction: transaction.clone(),
+            outcome,
+        };
+        let json = serde_json::to_string(&record).map_err(io::Error::from)?;
+        let line = format!("{:08x} {}\n", crc32fast::hash(json.as_bytes()), json);
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.next_lsn += 1;
+        Ok(record.lsn)
+    }
+}
+
+///
+/// Read the good records from a write-ahead log
+///
+/// A log that doesn't exist has no records.
+///
+/// # Arguments
+///
+/// * `filename`: log file
+///
+/// # Returns
+///
+/// * `Result<(Vec<WalRecord>, u64), PaymentError>`: the records up to the
+///   first bad one, and the length in bytes of that good part of the file.
+///
+pub fn read_wal(filename: &str) -> Result<(Vec<WalRecord>, u64), PaymentError> {
+    let mut contents = Vec::new();
+    match File::open(filename) {
+        Ok(mut file) => {
+            file.read_to_end(&mut contents)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut records: Vec<WalRecord> = Vec::new();
+    let mut valid_len: usize = 0;
+    while let Some(end) = contents[valid_len..].iter().position(|b| *b == b'\n') {
+        let line = &contents[valid_len..valid_len + end];
+        match parse_record(line) {
+            // Records have to follow on from each other
+            Some(record) if record.lsn == records.last().map_or(1, |last| last.lsn + 1) => {
+                records.push(record);
+                valid_len += end + 1;
+            }
+            _ => break,
+        }
+    }
+    Ok((records, valid_len as u64))
+}
+
+//
+// parse_record - a single line of the log without its newline. None if the
+// checksum doesn't match or it isn't a record.
+//
+fn parse_record(line: &[u8]) -> Option<WalRecord> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (checksum, json) = line.split_once(' ')?;
+    let checksum = u32::from_str_radix(checksum, 16).ok()?;
+    if checksum != crc32fast::hash(json.as_bytes()) {
+        return None;
+    }
+    serde_json::from_str(json).ok()
+}
+
+///
+/// Rebuild a ledger from a snapshot and the tail of the write-ahead log
+///
+/// `ledger` is the starting point, either empty or loaded from the last
+/// snapshot (see `Ledger::load_ledger`), set up with the same options it
+/// was originally run with. Every log record after the ledger's `last_lsn`
+/// is replayed into it. A replay that doesn't reproduce the logged outcome
+/// is an error. The replayed transactions aren't counted in the ledger's
+/// stats, those are only for what this run reads.
+///
+/// # Arguments
+///
+/// * `ledger`: ledger to replay into
+/// * `filename`: write-ahead log file
+///
+/// # Returns
+///
+/// * `Result<Ledger, PaymentError>`: the recovered ledger,
+///   `WalOutcomeMismatch` or `WalBalanceMismatch` for the first record that
+///   doesn't replay as logged
+///
+pub fn recover(mut ledger: Ledger, filename: &str) -> Result<Ledger, PaymentError> {
+    let (records, _) = read_wal(filename)?;
+    // Don't log the replay itself, or count it in this run's stats
+    let wal = ledger.take_wal();
+    let stats = std::mem::take(&mut ledger.stats);
+
+    let mut replayed = 0;
+    for record in records {
+        if record.lsn <= ledger.last_lsn {
+            continue;
+        }
+        let result = if record.admin {
+            ledger.process_admin_transaction(&record.transaction)
+        } else {
+            ledger.process_transaction(&record.transaction)
+        };
+        match (&record.outcome, result) {
+            (WalOutcome::Accepted { account, .. }, Ok(())) => {
+                if ledger.by_client_id.get(&account.client) != Some(account) {
+                    return Err(PaymentError::WalBalanceMismatch(record.lsn));
+                }
+            }
+            (WalOutcome::Rejected { .. }, Err(_)) => {}
+            (_, result) => {
+                if let Err(ref err) = result {
+                    error!("Write-ahead log record {} rejected on replay: {}", record.lsn, err);
+                }
+                return Err(PaymentError::WalOutcomeMismatch {
+                    lsn: record.lsn,
+                    accepted: result.is_ok(),
+                });
+            }
+        }
+        ledger.last_lsn = record.lsn;
+        replayed += 1;
+    }
+
+    ledger.stats = stats;
+    if let Some(wal) = wal {
+        ledger.attach_wal(wal);
+    }
+    info!("Recovered {} records from write-ahead log {}", replayed, filename);
+    Ok(ledger)
+}
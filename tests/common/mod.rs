@@ -0,0 +1,56 @@
+//
+// Helpers shared by the integration tests
+//
+
+// Not every test file uses every helper
+#![allow(dead_code)]
+
+use payment_engine::{
+    error::PaymentError,
+    ledger::Ledger,
+    transaction::{Transaction, TransactionType},
+};
+use rust_decimal::Decimal;
+
+// Helper function to create a transaction, an empty amount means none. The
+// seq_num is 0, process_all (or whatever the test feeds it to) numbers them.
+pub fn create_transaction(
+    tx_type: TransactionType,
+    client_id: u16,
+    tx_id: u32,
+    amount: &str,
+) -> Transaction {
+    Transaction {
+        seq_num: 0,
+        tx_type,
+        client_id,
+        tx_id,
+        amount: if amount.is_empty() {
+            None
+        } else {
+            Some(Decimal::from_str_exact(amount).unwrap())
+        },
+        timestamp: None,
+        destination_id: None,
+    }
+}
+
+// Process transactions in order, numbering them as a reader would. The
+// results are returned rather than stopping at the first error.
+pub fn process_all(
+    ledger: &mut Ledger,
+    transactions: Vec<Transaction>,
+) -> Vec<Result<(), PaymentError>> {
+    transactions
+        .into_iter()
+        .enumerate()
+        .map(|(seq_num, mut transaction)| {
+            transaction.seq_num = seq_num as u32;
+            if transaction.tx_type.is_admin() {
+                ledger.process_admin_transaction(&transaction)
+            } else {
+                ledger.process_transaction(&transaction)
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,161 @@
+//
+// Client balances as they were at an earlier seq_num
+//
+
+// # Tests:
+use payment_engine::{
+    account::AccountStatus,
+    ledger::{Ledger, OutputFormat, SortBy},
+    shard::{merge_ledgers, split_ledger},
+    transaction::{Transaction, TransactionType},
+};
+use std::collections::HashMap;
+use std::error::Error;
+use tempfile::NamedTempFile;
+
+mod common;
+use common::create_transaction;
+
+// A few thousand transactions over 5 clients. Deposits and withdrawals (some declined), disputes that are
+// resolved or charged back, and freezes/unlocks, numbered in order.
+fn history() -> Vec<Transaction> {
+    let mut transactions = Vec::new();
+    for i in 0..5000u32 {
+        // each block of 20 is for one client, in turn
+        let client_id = ((i / 20) % 5 + 1) as u16;
+        let transaction = match i % 20 {
+            0..=9 => create_transaction(TransactionType::Deposit, client_id, i, "10.25"),
+            10..=13 => create_transaction(TransactionType::Withdrawl, client_id, i, "30"),
+            14 | 15 => create_transaction(TransactionType::Dispute, client_id, i - 14, ""),
+            16 => create_transaction(TransactionType::Resolve, client_id, i - 16, ""),
+            17 => create_transaction(TransactionType::Chargeback, client_id, i - 16, ""),
+            18 => create_transaction(TransactionType::Freeze, client_id, i, ""),
+            // half the time the account is left locked
+            _ if (i / 20) % 2 == 0 => create_transaction(TransactionType::Unlock, client_id, i, ""),
+            _ => create_transaction(TransactionType::Deposit, client_id, i, "1"),
+        };
+        transactions.push(transaction);
+    }
+    for (seq_num, transaction) in transactions.iter_mut().enumerate() {
+        transaction.seq_num = seq_num as u32;
+    }
+    transactions
+}
+
+fn process(ledger: &mut Ledger, transaction: &Transaction) {
+    let _ = if transaction.tx_type.is_admin() {
+        ledger.process_admin_transaction(transaction)
+    } else {
+        ledger.process_transaction(transaction)
+    };
+}
+
+// The same as `balances_as_of` should give, the hard way: a fresh ledger
+// given everything up to and including seq_num. Clients it only has from
+// rejected transactions are left out.
+fn replayed_as_of(transactions: &[Transaction], seq_num: u32) -> HashMap<u16, AccountStatus> {
+    let mut ledger = Ledger::new();
+    for transaction in transactions.iter().filter(|t| t.seq_num <= seq_num) {
+        process(&mut ledger, transaction);
+    }
+    ledger
+        .by_client_id
+        .into_iter()
+        .filter(|(client_id, _)| {
+            ledger
+                .journal
+                .entries()
+                .iter()
+                .any(|entry| entry.client_id == *client_id)
+        })
+        .collect()
+}
+
+//
+// * Balances as of points through the history match replaying it up to
+//   that point
+// * Past the end it's the current balances
+// * The same after a dump and load, and after sharding
+//
+#[test]
+fn test_balances_as_of() -> Result<(), Box<dyn Error>> {
+    let transactions = history();
+    let mut ledger = Ledger::new();
+    for transaction in &transactions {
+        process(&mut ledger, transaction);
+    }
+    assert!(ledger.journal.entries().len() > 2048);
+
+    // the start, the end, and a few points in between
+    let mut points = vec![0, 1, 17, 4999];
+    for index in [1022, 1023, 1024, 2047, 2048] {
+        points.push(ledger.journal.entries()[index].seq_num().unwrap());
+    }
+    for &seq_num in &points {
+        assert_eq!(
+            ledger.balances_as_of(seq_num),
+            replayed_as_of(&transactions, seq_num),
+            "as of {}",
+            seq_num
+        );
+    }
+    assert_eq!(ledger.balances_as_of(u32::MAX), ledger.by_client_id);
+
+    let statelog = NamedTempFile::new()?;
+    let filename = statelog
+        .path()
+        .to_str()
+        .ok_or("Failed to get temp file path")?;
+    ledger.dump_ledger(filename)?;
+    let loaded = Ledger::load_ledger(filename)?;
+    let merged = merge_ledgers(split_ledger(Ledger::load_ledger(filename)?, 3));
+    for seq_num in points {
+        assert_eq!(
+            loaded.balances_as_of(seq_num),
+            ledger.balances_as_of(seq_num)
+        );
+        assert_eq!(
+            merged.balances_as_of(seq_num),
+            ledger.balances_as_of(seq_num)
+        );
+    }
+    Ok(())
+}
+
+//
+// * Client 1 deposits, client 2 deposits, client 1 withdraws
+// * As of the first deposit only client 1 is there, before the withdrawal
+//   client 1 still has all of their deposit
+//
+#[test]
+fn test_dump_balances_as_of() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    let mut transactions = [
+        create_transaction(TransactionType::Deposit, 1, 1, "5.0"),
+        create_transaction(TransactionType::Deposit, 2, 2, "1.0"),
+        create_transaction(TransactionType::Withdrawl, 1, 3, "2.0"),
+    ];
+    for (seq_num, transaction) in transactions.iter_mut().enumerate() {
+        transaction.seq_num = seq_num as u32;
+        ledger.process_transaction(transaction)?;
+    }
+
+    let output = |seq_num: u32| -> Result<String, Box<dyn Error>> {
+        let mut out = Vec::new();
+        ledger.dump_balances_as_of(&mut out, seq_num, OutputFormat::Csv, SortBy::Client)?;
+        Ok(String::from_utf8(out)?)
+    };
+    assert_eq!(
+        output(0)?,
+        "client,available,held,total,locked\n1,5.0,0,5.0,false\n"
+    );
+    assert_eq!(
+        output(1)?,
+        "client,available,held,total,locked\n1,5.0,0,5.0,false\n2,1.0,0,1.0,false\n"
+    );
+    assert_eq!(
+        output(2)?,
+        "client,available,held,total,locked\n1,3.0,0,3.0,false\n2,1.0,0,1.0,false\n"
+    );
+    Ok(())
+}
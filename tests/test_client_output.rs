@@ -0,0 +1,138 @@
+//
+// Writing the client balances as csv, json and json lines, and their order
+//
+
+// # Tests:
+use payment_engine::{
+    ledger::{Ledger, OutputFormat, SortBy},
+    transaction::TransactionType,
+};
+use std::error::Error;
+
+mod common;
+use common::create_transaction;
+
+// Client 3 has funds held, client 1 is locked
+fn sample_ledger() -> Result<Ledger, Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 3, 1, "10.5"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 3, 2, "0.0001"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 3, 2, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 3, "4"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 3, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 3, ""))?;
+    Ok(ledger)
+}
+
+fn output(ledger: &Ledger, format: OutputFormat) -> Result<String, Box<dyn Error>> {
+    let mut out = Vec::new();
+    ledger.dump_clients(&mut out, format, SortBy::Client)?;
+    Ok(String::from_utf8(out)?)
+}
+
+//
+// * CSV has a header and a row per client in client order
+//
+#[test]
+fn test_csv_output() -> Result<(), Box<dyn Error>> {
+    let ledger = sample_ledger()?;
+    assert_eq!(
+        output(&ledger, OutputFormat::Csv)?,
+        "client,available,held,total,locked\n1,0,0,0,true\n3,10.5000,0.0001,10.5001,false\n"
+    );
+    Ok(())
+}
+
+//
+// * JSON is an array of the same rows, amounts as strings
+//
+#[test]
+fn test_json_output() -> Result<(), Box<dyn Error>> {
+    let ledger = sample_ledger()?;
+    let value: serde_json::Value = serde_json::from_str(&output(&ledger, OutputFormat::Json)?)?;
+    assert_eq!(
+        value,
+        serde_json::json!([
+            {"client": 1, "available": "0", "held": "0", "total": "0", "locked": true},
+            {"client": 3, "available": "10.5000", "held": "0.0001", "total": "10.5001", "locked": false},
+        ])
+    );
+
+    assert_eq!(output(&Ledger::new(), OutputFormat::Json)?.trim(), "[]");
+    Ok(())
+}
+
+//
+// * JSON Lines is one object per client, in client order
+//
+#[test]
+fn test_jsonl_output() -> Result<(), Box<dyn Error>> {
+    let ledger = sample_ledger()?;
+    assert_eq!(
+        output(&ledger, OutputFormat::Jsonl)?,
+        concat!(
+            "{\"client\":1,\"available\":\"0\",\"held\":\"0\",\"total\":\"0\",\"locked\":true}\n",
+            "{\"client\":3,\"available\":\"10.5000\",\"held\":\"0.0001\",\"total\":\"10.5001\",\"locked\":false}\n",
+        )
+    );
+
+    assert_eq!(output(&Ledger::new(), OutputFormat::Jsonl)?, "");
+    Ok(())
+}
+
+// Clients 5, 2 and 9 have the same total, spread differently over
+// available and held
+fn tied_ledger() -> Result<Ledger, Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 5, 1, "20"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 2, "15"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 3, "5"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 2, 3, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 9, 4, "20"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 9, 4, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 5, "30"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 7, 6, "0.5"))?;
+    Ok(ledger)
+}
+
+fn client_order(ledger: &Ledger, sort_by: SortBy) -> Result<Vec<u16>, Box<dyn Error>> {
+    let mut out = Vec::new();
+    ledger.dump_clients(&mut out, OutputFormat::Jsonl, sort_by)?;
+    let mut clients = Vec::new();
+    for line in String::from_utf8(out)?.lines() {
+        let row: serde_json::Value = serde_json::from_str(line)?;
+        clients.push(row["client"].as_u64().unwrap() as u16);
+    }
+    Ok(clients)
+}
+
+//
+// * Each sort order is ascending, ties are in client id order
+//
+#[test]
+fn test_sort_by() -> Result<(), Box<dyn Error>> {
+    let ledger = tied_ledger()?;
+    assert_eq!(client_order(&ledger, SortBy::Client)?, vec![1, 2, 5, 7, 9]);
+    assert_eq!(client_order(&ledger, SortBy::Total)?, vec![7, 2, 5, 9, 1]);
+    assert_eq!(client_order(&ledger, SortBy::Available)?, vec![9, 7, 2, 5, 1]);
+    assert_eq!(client_order(&ledger, SortBy::Held)?, vec![1, 5, 7, 2, 9]);
+    Ok(())
+}
+
+//
+// * The same input gives byte for byte the same output every time, even
+//   though each ledger's HashMaps iterate in a different order
+//
+#[test]
+fn test_output_is_reproducible() -> Result<(), Box<dyn Error>> {
+    for sort_by in [SortBy::Client, SortBy::Total, SortBy::Available, SortBy::Held] {
+        let mut first = Vec::new();
+        tied_ledger()?.dump_clients(&mut first, OutputFormat::Csv, sort_by)?;
+        for _ in 0..20 {
+            let mut again = Vec::new();
+            tied_ledger()?.dump_clients(&mut again, OutputFormat::Csv, sort_by)?;
+            assert_eq!(again, first);
+        }
+    }
+    Ok(())
+}
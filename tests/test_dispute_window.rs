@@ -0,0 +1,190 @@
+//
+// Dispute windows, and resolving disputes left open too long
+//
+
+// # Tests:
+use chrono::DateTime;
+use clap::Parser;
+use payment_engine::{
+    args::Args,
+    error::PaymentError,
+    ledger::{DisputeState, DisputeWindow, Ledger},
+    transaction::{Transaction, TransactionType},
+    wal::{self, Wal},
+};
+use rust_decimal::dec;
+use std::error::Error;
+use tempfile::NamedTempFile;
+
+mod common;
+use common::{create_transaction, process_all};
+
+// The same transaction, timestamped `seconds` after the epoch
+fn at(mut transaction: Transaction, seconds: i64) -> Transaction {
+    transaction.timestamp = DateTime::from_timestamp(seconds, 0);
+    transaction
+}
+
+fn is_window_closed(result: &Result<(), PaymentError>) -> bool {
+    matches!(result, Err(PaymentError::DisputeWindowClosed { .. }))
+}
+
+//
+// * Window of 3 seq_nums: a dispute 3 after its deposit is in time, one 4
+//   after is rejected and the deposit stays processed
+// * Without timestamps the time limit doesn't apply
+//
+#[test]
+fn test_dispute_window_seq_nums() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.dispute_window = DisputeWindow {
+        seconds: Some(1),
+        seq_nums: Some(3),
+    };
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "10"),
+            create_transaction(TransactionType::Deposit, 1, 2, "10"),
+            create_transaction(TransactionType::Deposit, 2, 3, "10"),
+            create_transaction(TransactionType::Dispute, 1, 1, ""),
+            create_transaction(TransactionType::Deposit, 2, 4, "10"),
+            create_transaction(TransactionType::Dispute, 1, 2, ""),
+            create_transaction(TransactionType::Resolve, 1, 1, ""),
+        ],
+    );
+    assert!(results[3].is_ok());
+    assert!(is_window_closed(&results[5]));
+    assert_eq!(
+        results[5].as_ref().unwrap_err().reason_code(),
+        "dispute_window_closed"
+    );
+    assert!(results[6].is_ok());
+    assert_eq!(
+        ledger.by_transaction_id.get(&2).unwrap().state,
+        DisputeState::Processed
+    );
+    let account = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!((account.available, account.held), (dec!(20), dec!(0)));
+    Ok(())
+}
+
+//
+// * Window of a day: disputes timestamped exactly a day after their deposit
+//   are in time, a second later is too late, however close the seq_nums
+// * A dispute without a timestamp falls back to the seq_num limit
+//
+#[test]
+fn test_dispute_window_timestamps() -> Result<(), Box<dyn Error>> {
+    let day = 24 * 60 * 60;
+    let mut ledger = Ledger::new();
+    ledger.dispute_window = DisputeWindow {
+        seconds: Some(day),
+        seq_nums: Some(1),
+    };
+    let results = process_all(
+        &mut ledger,
+        vec![
+            at(create_transaction(TransactionType::Deposit, 1, 1, "10"), 0),
+            at(create_transaction(TransactionType::Dispute, 1, 1, ""), day),
+            at(create_transaction(TransactionType::Deposit, 1, 2, "10"), 0),
+            at(
+                create_transaction(TransactionType::Dispute, 1, 2, ""),
+                day + 1,
+            ),
+            at(create_transaction(TransactionType::Deposit, 1, 3, "10"), 0),
+            create_transaction(TransactionType::Deposit, 1, 4, "10"),
+            create_transaction(TransactionType::Dispute, 1, 3, ""),
+        ],
+    );
+    assert!(results[1].is_ok());
+    assert!(is_window_closed(&results[3]));
+    // seq_num 6 is 2 after the deposit
+    assert!(is_window_closed(&results[6]));
+    Ok(())
+}
+
+//
+// * Deadline of 2 seq_nums: a chargeback 3 after its dispute is rejected,
+//   the funds stay held
+// * At the end those disputes are resolved, with a resolve numbered with
+//   the last seq_num on the journal. A dispute still within the deadline
+//   stays open, and doing it again resolves nothing more
+// * The resolve is in the write-ahead log, replaying it gives the same
+//   ledger
+//
+#[test]
+fn test_dispute_deadline() -> Result<(), Box<dyn Error>> {
+    let wal_file = NamedTempFile::new()?;
+    let filename = wal_file
+        .path()
+        .to_str()
+        .ok_or("Failed to get temp file path")?;
+    let deadline = DisputeWindow {
+        seconds: None,
+        seq_nums: Some(2),
+    };
+    let mut ledger = Ledger::new();
+    ledger.dispute_deadline = deadline;
+    ledger.attach_wal(Wal::open(filename)?);
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "10"),
+            create_transaction(TransactionType::Dispute, 1, 1, ""),
+            create_transaction(TransactionType::Deposit, 2, 2, "5"),
+            create_transaction(TransactionType::Deposit, 2, 3, "5"),
+            create_transaction(TransactionType::Chargeback, 1, 1, ""),
+            create_transaction(TransactionType::Dispute, 2, 2, ""),
+        ],
+    );
+    assert!(is_window_closed(&results[4]));
+    assert_eq!(ledger.by_client_id.get(&1).unwrap().held, dec!(10));
+    assert!(!ledger.by_client_id.get(&1).unwrap().locked);
+
+    assert_eq!(ledger.resolve_expired_disputes()?, 1);
+    assert_eq!(ledger.resolve_expired_disputes()?, 0);
+    assert_eq!(ledger.stats.disputes_auto_resolved, 1);
+    let record = ledger.by_transaction_id.get(&1).unwrap();
+    assert_eq!(record.state, DisputeState::Resolved);
+    assert_eq!(record.disputed_at.unwrap().seq_num, 1);
+    assert_eq!(
+        ledger.by_transaction_id.get(&2).unwrap().state,
+        DisputeState::Disputed
+    );
+    let account = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!((account.available, account.held), (dec!(10), dec!(0)));
+    let last = ledger.statement(1)?.pop().unwrap();
+    assert_eq!(
+        (last.seq_num, last.tx_type.as_str(), last.tx),
+        (Some(5), "resolve", Some(1))
+    );
+    ledger.check_journal()?;
+
+    let mut replayed = Ledger::new();
+    replayed.dispute_deadline = deadline;
+    let replayed = wal::recover(replayed, filename)?;
+    assert_eq!(replayed.by_client_id, ledger.by_client_id);
+    assert_eq!(replayed.journal.entries(), ledger.journal.entries());
+    Ok(())
+}
+
+//
+// * Durations are seconds, or a number of seconds, minutes, hours or days
+// * Anything else is refused
+//
+#[test]
+fn test_dispute_window_args() {
+    let parse = |window: &str| {
+        Args::try_parse_from(["payment_engine", "--dispute-window", window, "input.csv"])
+            .map(|args| args.dispute_window)
+    };
+    assert_eq!(parse("45").unwrap(), Some(45));
+    assert_eq!(parse("45s").unwrap(), Some(45));
+    assert_eq!(parse("10m").unwrap(), Some(600));
+    assert_eq!(parse("12h").unwrap(), Some(43200));
+    assert_eq!(parse("90d").unwrap(), Some(7776000));
+    for bad in ["", "d", "-1d", "1w", "1.5h"] {
+        assert!(parse(bad).is_err(), "{}", bad);
+    }
+}
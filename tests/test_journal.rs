@@ -0,0 +1,244 @@
+//
+// The double-entry journal behind the client balances
+//
+
+// # Tests:
+use payment_engine::{
+    error::PaymentError,
+    journal::{EntrySource, JournalAccount, Side},
+    ledger::{DisputePolicy, DisputeState, Ledger},
+    shard::{merge_ledgers, split_ledger},
+    transaction::TransactionType,
+};
+use rust_decimal::{dec, Decimal};
+use std::error::Error;
+use std::fs;
+use tempfile::NamedTempFile;
+
+mod common;
+use common::{create_transaction, process_all};
+
+//
+// * Client 1 deposits $100 tx_id = 1, withdraws $30 tx_id = 2
+// * Client 2 deposits $20 tx_id = 3, disputes and resolves it
+// * Client 3 deposits $10 tx_id = 4, disputes and charges it back
+// * A rejected withdrawal posts nothing, a freeze posts an empty entry
+// * Each entry balances, the client balances and the house accounts are
+//   what the entries add up to
+//
+#[test]
+fn test_journal_entries() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "100"),
+            create_transaction(TransactionType::Withdrawl, 1, 2, "30"),
+            create_transaction(TransactionType::Deposit, 2, 3, "20"),
+            create_transaction(TransactionType::Dispute, 2, 3, ""),
+            create_transaction(TransactionType::Resolve, 2, 3, ""),
+            create_transaction(TransactionType::Withdrawl, 1, 5, "1000"),
+            create_transaction(TransactionType::Deposit, 3, 4, "10"),
+            create_transaction(TransactionType::Dispute, 3, 4, ""),
+            create_transaction(TransactionType::Chargeback, 3, 4, ""),
+            create_transaction(TransactionType::Freeze, 1, 6, ""),
+        ],
+    );
+    assert!(matches!(
+        results[5],
+        Err(PaymentError::InsufficientFunds { .. })
+    ));
+
+    let entries = ledger.journal.entries();
+    assert_eq!(entries.len(), 9);
+    for entry in &entries[..8] {
+        assert_eq!(entry.lines.len(), 2);
+        entry.check_balanced()?;
+    }
+    // the freeze is there, it just doesn't move anything
+    assert!(entries[8].lines.is_empty());
+    // the withdrawal
+    assert_eq!(
+        entries[1].source,
+        EntrySource::Transaction {
+            seq_num: 1,
+            tx_id: 2,
+            tx_type: TransactionType::Withdrawl,
+            dispute_state: Some(DisputeState::Processed),
+        }
+    );
+    assert_eq!(
+        entries[1].lines[0].account,
+        JournalAccount::ClientAvailable(1)
+    );
+    assert_eq!(entries[1].lines[0].side, Side::Debit);
+    assert_eq!(entries[1].lines[1].account, JournalAccount::Settlement);
+    assert_eq!(entries[1].lines[1].side, Side::Credit);
+
+    assert_eq!(ledger.journal.check()?, (dec!(220), dec!(220)));
+    ledger.check_journal()?;
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::Settlement),
+        dec!(90)
+    );
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::ChargebackLoss),
+        Decimal::ZERO
+    );
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::ClientAvailable(1)),
+        dec!(70)
+    );
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::ClientHeld(3)),
+        Decimal::ZERO
+    );
+
+    // Sharding splits the journal by client and puts it back in order
+    let merged = merge_ledgers(split_ledger(ledger, 2));
+    merged.check_journal()?;
+    let seq_nums: Vec<Option<u32>> = merged
+        .journal
+        .entries()
+        .iter()
+        .map(|e| e.seq_num())
+        .collect();
+    assert_eq!(
+        seq_nums,
+        vec![
+            Some(0),
+            Some(1),
+            Some(2),
+            Some(3),
+            Some(4),
+            Some(6),
+            Some(7),
+            Some(8),
+            Some(9)
+        ]
+    );
+    Ok(())
+}
+
+//
+// * Withdrawals as reversals: client 1 deposits $50, withdraws $20 and
+//   disputes the withdrawal, the claim is held against the house
+// * Resolving it takes the claim away again, charging back a second
+//   disputed withdrawal leaves the loss with the house
+//
+#[test]
+fn test_journal_withdrawal_reversal() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.dispute_policy = DisputePolicy::WithdrawalsAsReversal;
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "50"),
+            create_transaction(TransactionType::Withdrawl, 1, 2, "20"),
+            create_transaction(TransactionType::Dispute, 1, 2, ""),
+        ],
+    );
+    assert!(results.iter().all(|r| r.is_ok()));
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::ChargebackLoss),
+        dec!(20)
+    );
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::ClientHeld(1)),
+        dec!(20)
+    );
+    ledger.check_journal()?;
+
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Resolve, 1, 2, ""),
+            create_transaction(TransactionType::Withdrawl, 1, 3, "5"),
+            create_transaction(TransactionType::Dispute, 1, 3, ""),
+            create_transaction(TransactionType::Chargeback, 1, 3, ""),
+        ],
+    );
+    assert!(results.iter().all(|r| r.is_ok()));
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::ChargebackLoss),
+        dec!(5)
+    );
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::Settlement),
+        dec!(25)
+    );
+    let account = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(account.available, dec!(30));
+    assert_eq!(account.held, Decimal::ZERO);
+    ledger.check_journal()?;
+    Ok(())
+}
+
+//
+// * The journal goes in the dump and comes back with its balances
+// * A dump from before the journal gets opening balances, including a
+//   negative available balance
+// * A dump whose balances don't match its journal is refused
+//
+#[test]
+fn test_journal_dump_and_load() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "100"),
+            create_transaction(TransactionType::Withdrawl, 1, 2, "80"),
+            create_transaction(TransactionType::Dispute, 1, 1, ""),
+            create_transaction(TransactionType::Deposit, 2, 3, "7.5"),
+        ],
+    );
+    let statelog = NamedTempFile::new()?;
+    let filename = statelog
+        .path()
+        .to_str()
+        .ok_or("Failed to get temp file path")?;
+    ledger.dump_ledger(filename)?;
+    let loaded = Ledger::load_ledger(filename)?;
+    assert_eq!(loaded.journal.entries(), ledger.journal.entries());
+    assert_eq!(
+        loaded.journal.balance(&JournalAccount::Settlement),
+        dec!(27.5)
+    );
+
+    // Without its journal, as a version 1 dump from before it would be
+    let mut dump: serde_json::Value = serde_json::from_str(&fs::read_to_string(filename)?)?;
+    dump["version"] = 1.into();
+    dump["ledger"].as_object_mut().unwrap().remove("journal");
+    fs::write(filename, serde_json::to_string(&dump)?)?;
+    let loaded = Ledger::load_ledger(filename)?;
+    assert_eq!(loaded.journal.entries().len(), 2);
+    assert!(loaded
+        .journal
+        .entries()
+        .iter()
+        .all(|e| e.source == EntrySource::OpeningBalance { locked: false }));
+    assert_eq!(
+        loaded.journal.balance(&JournalAccount::ClientAvailable(1)),
+        dec!(-80)
+    );
+    assert_eq!(
+        loaded.journal.balance(&JournalAccount::ClientHeld(1)),
+        dec!(100)
+    );
+    assert_eq!(
+        loaded.journal.balance(&JournalAccount::Settlement),
+        dec!(27.5)
+    );
+
+    // A balance that the journal doesn't account for
+    ledger.dump_ledger(filename)?;
+    let mut dump: serde_json::Value = serde_json::from_str(&fs::read_to_string(filename)?)?;
+    dump["ledger"]["by_client_id"]["2"]["available"] = "1000".into();
+    fs::write(filename, serde_json::to_string(&dump)?)?;
+    let result = Ledger::load_ledger(filename);
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("Client 2 balances don't match the journal"));
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//
+// Dumping the ledger with --statelog and loading it back with --resume-from
+//
+
+// # Tests:
+#![allow(clippy::bool_assert_comparison)]
+use payment_engine::{
+    error::PaymentError,
+    ledger::{DisputeState, Ledger, LEDGER_FORMAT_VERSION},
+    transaction::{self, ReadSession, Transaction, TransactionType},
+};
+use rust_decimal::{dec, Decimal};
+use std::error::Error;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+mod common;
+use common::create_transaction;
+
+//
+// * Client 1 deposits $100 tx_id = 1 and $50 tx_id = 2, disputes tx_id = 1
+// * Client 2 deposits $10 tx_id = 3, disputes and charges it back
+// * Dump the ledger and load it back, everything should match
+// * Keep processing on the loaded ledger, resolving tx_id = 1
+//
+#[test]
+fn test_dump_and_load_ledger() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 2, "50.1234"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 3, "10"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 2, 3, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 2, 3, ""))?;
+
+    let statelog = NamedTempFile::new()?;
+    let filename = statelog.path().to_str().ok_or("Failed to get temp file path")?;
+    ledger.dump_ledger(filename)?;
+
+    let mut loaded = Ledger::load_ledger(filename)?;
+    assert_eq!(loaded.by_client_id.len(), 2);
+    assert_eq!(loaded.by_transaction_id.len(), 3);
+
+    let client1_status = loaded.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(50.1234));
+    assert_eq!(client1_status.held, dec!(100));
+    assert_eq!(client1_status.locked, false);
+
+    let client2_status = loaded.by_client_id.get(&2).unwrap();
+    assert_eq!(client2_status.available, Decimal::ZERO);
+    assert_eq!(client2_status.held, Decimal::ZERO);
+    assert_eq!(client2_status.locked, true);
+
+    let record = loaded.by_transaction_id.get(&1).unwrap();
+    assert_eq!(record.state, DisputeState::Disputed);
+    assert_eq!(record.transaction.amount, Some(dec!(100)));
+    assert_eq!(
+        loaded.by_transaction_id.get(&3).unwrap().state,
+        DisputeState::ChargedBack
+    );
+
+    // Carry on from where the first ledger left off
+    loaded.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, ""))?;
+    let client1_status = loaded.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(150.1234));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+
+    // tx ids from the earlier run are still taken
+    assert!(loaded
+        .process_transaction(&create_transaction(TransactionType::Deposit, 1, 2, "1"))
+        .is_err());
+    Ok(())
+}
+
+//
+// * The dump carries the format version
+// * A dump with a version we don't know about is refused, as is a header
+//   without a version
+//
+#[test]
+fn test_load_ledger_checks_version() -> Result<(), Box<dyn Error>> {
+    let ledger = Ledger::new();
+    let statelog = NamedTempFile::new()?;
+    let filename = statelog.path().to_str().ok_or("Failed to get temp file path")?;
+    ledger.dump_ledger(filename)?;
+
+    let dump: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(filename)?)?;
+    assert_eq!(dump["version"], LEDGER_FORMAT_VERSION);
+
+    let mut future = NamedTempFile::new()?;
+    write!(
+        future,
+        "{{\"version\": {}, \"ledger\": {{}}}}",
+        LEDGER_FORMAT_VERSION + 1
+    )?;
+    future.flush()?;
+    let result = Ledger::load_ledger(future.path().to_str().unwrap());
+    let err = result.unwrap_err();
+    assert!(matches!(
+        err,
+        PaymentError::UnsupportedLedgerVersion(v) if v == LEDGER_FORMAT_VERSION + 1
+    ));
+    assert!(err.to_string().contains("Unsupported ledger format version"));
+
+    let mut unversioned = NamedTempFile::new()?;
+    write!(unversioned, "{{\"ledger\": {{}}}}")?;
+    unversioned.flush()?;
+    let result = Ledger::load_ledger(unversioned.path().to_str().unwrap());
+    assert!(matches!(result, Err(PaymentError::MissingLedgerVersion)));
+    Ok(())
+}
+
+//
+// * Read a file into a ledger, its last seq_num is kept in the dump
+// * A session resumed from the loaded ledger numbers the next file after it
+// * A ledger that has seen nothing has no last seq_num
+//
+#[test]
+fn test_resume_seq_nums() -> Result<(), Box<dyn Error>> {
+    let csv_content = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\n";
+    let mut ledger = Ledger::new();
+    assert_eq!(ledger.last_seq_num, None);
+    let mut session = ReadSession::new();
+    transaction::process_csv_from_reader(
+        csv::Reader::from_reader(csv_content.as_bytes()),
+        &mut session,
+        |transaction: Transaction| ledger.process_transaction(&transaction),
+        true,
+        None,
+    )?;
+    // the rejected withdrawal still used up a seq_num
+    assert_eq!(ledger.last_seq_num, Some(1));
+
+    let statelog = NamedTempFile::new()?;
+    let filename = statelog.path().to_str().ok_or("Failed to get temp file path")?;
+    ledger.dump_ledger(filename)?;
+    let mut loaded = Ledger::load_ledger(filename)?;
+    assert_eq!(loaded.last_seq_num, Some(1));
+
+    let mut seq_nums = Vec::new();
+    let csv_content = "type,client,tx,amount\ndeposit,1,3,1.0\ndeposit,1,4,1.0\n";
+    transaction::process_csv_from_reader(
+        csv::Reader::from_reader(csv_content.as_bytes()),
+        &mut ReadSession::resume(loaded.last_seq_num),
+        |transaction: Transaction| {
+            seq_nums.push(transaction.seq_num);
+            loaded.process_transaction(&transaction)
+        },
+        false,
+        None,
+    )?;
+    assert_eq!(seq_nums, vec![2, 3]);
+    assert_eq!(loaded.last_seq_num, Some(3));
+    Ok(())
+}
+
+//
+// * A partly disputed deposit goes in the dump with how much is disputed,
+//   resolved and charged back
+// * A version 1 dump (whole transaction disputes, no breakdown) is migrated,
+//   each transaction's amount is put under its state
+//
+#[test]
+fn test_dispute_breakdown_dump_and_migration() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "40"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 2, "10"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 2, 2, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 2, 2, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 3, 3, "5"))?;
+    let statelog = NamedTempFile::new()?;
+    let filename = statelog.path().to_str().ok_or("Failed to get temp file path")?;
+    ledger.dump_ledger(filename)?;
+
+    let mut dump: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(filename)?)?;
+    let record = &dump["ledger"]["by_transaction_id"]["1"];
+    assert_eq!(record["disputed"], "40");
+    assert_eq!(record["resolved"], "0");
+    assert_eq!(record["charged_back"], "0");
+
+    // As version 1 would have had it, disputes were always of everything
+    dump["version"] = 1.into();
+    for record in dump["ledger"]["by_transaction_id"].as_object_mut().unwrap().values_mut() {
+        let record = record.as_object_mut().unwrap();
+        for field in ["disputed", "resolved", "charged_back"] {
+            record.remove(field);
+        }
+    }
+    dump["ledger"].as_object_mut().unwrap().remove("journal");
+    dump["ledger"]["by_client_id"]["1"]["available"] = "0".into();
+    dump["ledger"]["by_client_id"]["1"]["held"] = "100".into();
+    std::fs::write(filename, serde_json::to_string(&dump)?)?;
+    let loaded = Ledger::load_ledger(filename)?;
+    let breakdown = |tx_id: u32| {
+        let record = loaded.by_transaction_id.get(&tx_id).unwrap();
+        (record.disputed, record.resolved, record.charged_back)
+    };
+    assert_eq!(breakdown(1), (dec!(100), dec!(0), dec!(0)));
+    assert_eq!(breakdown(2), (dec!(0), dec!(10), dec!(0)));
+    assert_eq!(breakdown(3), (dec!(0), dec!(0), dec!(0)));
+    assert_eq!(
+        loaded.by_transaction_id.get(&2).unwrap().state,
+        DisputeState::Resolved
+    );
+    Ok(())
+}
+
+//
+// * A dump from before there was a version header is just the ledger, with
+//   the last transaction accepted for each tx_id: client 1's deposit
+//   tx_id = 2 was replaced by its dispute, client 2's tx_id = 3 by its
+//   chargeback
+// * It loads as version 1, the replaced transactions become deposits in the
+//   state their replacement says, and resolving the dispute still works
+//
+#[test]
+fn test_load_unversioned_dump() -> Result<(), Box<dyn Error>> {
+    let mut statelog = NamedTempFile::new()?;
+    write!(
+        statelog,
+        r#"{{
+  "by_client_id": {{
+    "1": {{"client": 1, "available": "60", "held": "40", "locked": false}},
+    "2": {{"client": 2, "available": "0", "held": "0", "locked": true}}
+  }},
+  "by_transaction_id": {{
+    "1": {{"seq_num": 0, "tx_type": "Deposit", "client_id": 1, "tx_id": 1, "amount": "50"}},
+    "2": {{"seq_num": 2, "tx_type": "Dispute", "client_id": 1, "tx_id": 2, "amount": "40"}},
+    "3": {{"seq_num": 5, "tx_type": "Chargeback", "client_id": 2, "tx_id": 3, "amount": "5"}}
+  }}
+}}"#
+    )?;
+    statelog.flush()?;
+    let mut loaded = Ledger::load_ledger(statelog.path().to_str().unwrap())?;
+    let state = |ledger: &Ledger, tx_id: u32| {
+        let record = ledger.by_transaction_id.get(&tx_id).unwrap();
+        (record.transaction.tx_type.clone(), record.state, record.disputed)
+    };
+    assert_eq!(
+        state(&loaded, 1),
+        (TransactionType::Deposit, DisputeState::Processed, dec!(0))
+    );
+    assert_eq!(
+        state(&loaded, 2),
+        (TransactionType::Deposit, DisputeState::Disputed, dec!(40))
+    );
+    assert_eq!(
+        state(&loaded, 3),
+        (TransactionType::Deposit, DisputeState::ChargedBack, dec!(0))
+    );
+    assert_eq!(loaded.by_transaction_id.get(&3).unwrap().charged_back, dec!(5));
+
+    loaded.process_transaction(&create_transaction(TransactionType::Resolve, 1, 2, ""))?;
+    let client1_status = loaded.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(100));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    loaded.check_journal()?;
+    Ok(())
+}
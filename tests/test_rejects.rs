@@ -0,0 +1,191 @@
+//
+// Writing rejected rows to a rejects (dead-letter) file
+//
+
+// # Tests:
+use clap::Parser;
+use payment_engine::{
+    args::Args,
+    error::PaymentError,
+    ledger::Ledger,
+    rejects::RejectWriter,
+    summary::ReadSummary,
+    transaction::{self, InputFormat, ReadSession, Transaction},
+};
+use std::error::Error;
+use std::fs;
+use tempfile::TempDir;
+
+// What processing returned, and the rows of the rejects file
+type RejectsRun = (Result<ReadSummary, PaymentError>, Vec<csv::StringRecord>);
+
+// Run `input` through a fresh ledger with a rejects file
+fn run_with_rejects(
+    input: &str,
+    extension: &str,
+    format: InputFormat,
+    keep_going: bool,
+) -> Result<RejectsRun, Box<dyn Error>> {
+    let dir = TempDir::new()?;
+    let input_file = dir.path().join(format!("input.{}", extension));
+    fs::write(&input_file, input)?;
+    let rejects_file = dir.path().join("rejects.csv");
+
+    let mut ledger = Ledger::new();
+    let mut rejects = RejectWriter::create(rejects_file.to_str().unwrap())?;
+    let result = transaction::process_file(
+        input_file.to_str().unwrap(),
+        format,
+        &mut ReadSession::new(),
+        |transaction: Transaction| ledger.process_transaction(&transaction),
+        keep_going,
+        Some(&mut rejects),
+    );
+    rejects.flush()?;
+
+    let mut rdr = csv::Reader::from_path(&rejects_file)?;
+    assert_eq!(
+        rdr.headers()?,
+        vec![
+            "file",
+            "line",
+            "seq_num",
+            "reason_code",
+            "message",
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "timestamp",
+            "destination"
+        ]
+    );
+    let rows = rdr.records().collect::<Result<Vec<_>, _>>()?;
+    for row in &rows {
+        assert_eq!(&row[0], input_file.to_str().unwrap());
+    }
+    Ok((result, rows))
+}
+
+//
+// * Parse errors have no seq_num, ledger rejections do
+// * Each row keeps its line in the file (the header is line 1), reason
+//   code and original fields
+// * Accepted rows aren't written
+//
+#[test]
+fn test_csv_rejects() -> Result<(), Box<dyn Error>> {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 10.0\n\
+                 deposit, Frog, 2, 1.0\n\
+                 withdrawal, 1, 3, 50\n\
+                 deposit, 1, 1, 5\n\
+                 deposit, 1, 4, 1.23456\n\
+                 dispute, 1, 99,\n\
+                 deposit, 1, 5, 1, 0, 2, extra\n";
+    let (result, rows) = run_with_rejects(input, "csv", InputFormat::Csv, true)?;
+    assert!(result.is_ok());
+
+    let summary: Vec<(&str, &str, &str)> = rows.iter().map(|r| (&r[1], &r[2], &r[3])).collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("3", "", "parse_error"),
+            ("4", "1", "insufficient_funds"),
+            ("5", "2", "duplicate_transaction"),
+            ("6", "", "parse_error"),
+            ("7", "3", "unknown_transaction"),
+            ("8", "", "parse_error"),
+        ]
+    );
+    assert_eq!(
+        rows[0].iter().skip(5).collect::<Vec<_>>(),
+        vec!["deposit", "Frog", "2", "1.0", "", ""]
+    );
+    assert_eq!(
+        rows[1].iter().skip(5).collect::<Vec<_>>(),
+        vec!["withdrawal", "1", "3", "50", "", ""]
+    );
+    assert!(rows[1][4].contains("can't withdraw 50"));
+    assert_eq!(
+        rows[4].iter().skip(5).collect::<Vec<_>>(),
+        vec!["dispute", "1", "99", "", "", ""]
+    );
+    Ok(())
+}
+
+//
+// * JSON Lines rejects get the fields out of the object, even when it's
+//   the object that's wrong
+// * A line that isn't json at all still gets a row, without fields
+//
+#[test]
+fn test_jsonl_rejects() -> Result<(), Box<dyn Error>> {
+    let input = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"10\"}\n\
+                 {\"type\": \"deposit\", \"client\": 1, \"tx\": [2], \"amount\": \"1\"}\n\
+                 not json\n\
+                 {\"type\": \"withdrawal\", \"client\": 1, \"tx\": 3, \"amount\": 20}\n";
+    let (result, rows) = run_with_rejects(input, "jsonl", InputFormat::Jsonl, true)?;
+    assert!(result.is_ok());
+    assert_eq!(rows.len(), 3);
+
+    assert_eq!(&rows[0][1], "2");
+    assert_eq!(&rows[0][3], "parse_error");
+    assert_eq!(
+        rows[0].iter().skip(5).collect::<Vec<_>>(),
+        vec!["deposit", "1", "[2]", "1", "", ""]
+    );
+
+    assert_eq!(&rows[1][1], "3");
+    assert_eq!(&rows[1][3], "parse_error");
+    assert_eq!(
+        rows[1].iter().skip(5).collect::<Vec<_>>(),
+        vec!["", "", "", "", "", ""]
+    );
+
+    assert_eq!(&rows[2][1], "4");
+    assert_eq!(&rows[2][3], "insufficient_funds");
+    assert_eq!(
+        rows[2].iter().skip(5).collect::<Vec<_>>(),
+        vec!["withdrawal", "1", "3", "20", "", ""]
+    );
+    Ok(())
+}
+
+//
+// * Stopping on an error still writes the row that stopped processing
+//
+#[test]
+fn test_rejects_when_stopping_on_error() -> Result<(), Box<dyn Error>> {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 10.0\n\
+                 withdrawal, 1, 2, 50\n\
+                 deposit, Frog, 3, 1.0\n";
+    let (result, rows) = run_with_rejects(input, "csv", InputFormat::Csv, false)?;
+    assert!(matches!(result, Err(PaymentError::InsufficientFunds { .. })));
+    assert_eq!(rows.len(), 1);
+    assert_eq!(&rows[0][1], "3");
+    assert_eq!(&rows[0][3], "insufficient_funds");
+    Ok(())
+}
+
+//
+// * --rejects can be used with a single thread
+// * It's refused with more than one
+//
+#[test]
+fn test_rejects_args() {
+    let parse = |threads: &str| {
+        let args = Args::try_parse_from([
+            "payment_engine",
+            "--rejects",
+            "rejects.csv",
+            "--threads",
+            threads,
+            "input.csv",
+        ])?;
+        args.validate()
+    };
+    assert!(parse("1").is_ok());
+    assert!(parse("2").is_err());
+}
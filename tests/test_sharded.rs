@@ -0,0 +1,215 @@
+//
+// Sharded (multi-threaded) processing against the single-threaded ledger
+//
+
+// # Tests:
+use payment_engine::{
+    error::PaymentError,
+    ledger::{DisputePolicy, Ledger, SortBy},
+    shard::{self, ShardedLedger},
+    transaction::{Transaction, TransactionType},
+};
+use std::error::Error;
+
+mod common;
+use common::create_transaction;
+
+// Pseudo-random mix of every transaction type over a handful of clients.
+// Disputes etc. mostly refer back to the client's own earlier transactions,
+// but now and then a deposit/withdrawal/transfer reuses another client's
+// tx_id or a dispute is for another client's transaction. Transfers go to
+// any client, usually one on another shard.
+fn workload(count: u32) -> Vec<Transaction> {
+    let mut seed: u64 = 7;
+    let mut next = |limit: u64| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) % limit
+    };
+    let mut by_client: Vec<Vec<u32>> = vec![Vec::new(); 50];
+    let mut all: Vec<u32> = Vec::new();
+    let mut transactions = Vec::new();
+    for tx_id in 1..=count {
+        let client_id = next(50) as u16;
+        let amount = format!("{}.{:04}", next(500), next(10000));
+        let tx_type = match next(11) {
+            0..=3 => TransactionType::Deposit,
+            4..=5 => TransactionType::Withdrawl,
+            6..=7 => TransactionType::Dispute,
+            8 => TransactionType::Resolve,
+            9 => TransactionType::Chargeback,
+            _ => TransactionType::Transfer,
+        };
+        let mut transaction = match tx_type {
+            TransactionType::Deposit | TransactionType::Withdrawl | TransactionType::Transfer => {
+                let tx_id = if !all.is_empty() && next(10) == 0 {
+                    all[next(all.len() as u64) as usize]
+                } else {
+                    tx_id
+                };
+                by_client[client_id as usize].push(tx_id);
+                all.push(tx_id);
+                create_transaction(tx_type, client_id, tx_id, &amount)
+            }
+            _ => {
+                let earlier = if next(10) == 0 {
+                    &all
+                } else {
+                    &by_client[client_id as usize]
+                };
+                let referenced = if earlier.is_empty() {
+                    tx_id // unknown transaction
+                } else {
+                    earlier[next(earlier.len() as u64) as usize]
+                };
+                create_transaction(tx_type, client_id, referenced, "")
+            }
+        };
+        if transaction.tx_type == TransactionType::Transfer {
+            transaction.destination_id = Some(next(50) as u16);
+        }
+        transactions.push(transaction);
+    }
+    transactions
+}
+
+// The client csv, as main writes it
+fn client_csv(ledger: &Ledger) -> Result<String, Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    ledger.dump_client_csv(&mut wtr, SortBy::Client)?;
+    Ok(String::from_utf8(wtr.into_inner()?)?)
+}
+
+//
+// * Run the same workload single threaded and sharded over 1, 2, 4 and 7
+//   threads, with both dispute policies that change balances
+// * The client output, run totals and rejections by reason are identical
+//
+#[test]
+fn test_sharded_matches_single_threaded() -> Result<(), Box<dyn Error>> {
+    let transactions = workload(5000);
+    for policy in [DisputePolicy::DepositsOnly, DisputePolicy::WithdrawalsAsReversal] {
+        let mut single = Ledger::new();
+        single.dispute_policy = policy;
+        for transaction in &transactions {
+            let _ = single.process_transaction(transaction);
+        }
+        let expected = client_csv(&single)?;
+
+        for threads in [1, 2, 4, 7] {
+            let mut ledger = Ledger::new();
+            ledger.dispute_policy = policy;
+            let mut sharded = ShardedLedger::new(ledger, threads, true);
+            for transaction in &transactions {
+                sharded.process_transaction(transaction.clone())?;
+            }
+            let merged = sharded.finish()?;
+            assert_eq!(client_csv(&merged)?, expected, "{} threads", threads);
+            assert_eq!(merged.stats, single.stats, "{} threads", threads);
+            assert_eq!(
+                merged.by_transaction_id.len(),
+                single.by_transaction_id.len()
+            );
+            merged.check_journal()?;
+        }
+    }
+    Ok(())
+}
+
+//
+// * Process half the workload, split the ledger across shards and process
+//   the rest sharded (as --resume-from with --threads does)
+// * Same result as doing it all single threaded
+//
+#[test]
+fn test_sharded_resume() -> Result<(), Box<dyn Error>> {
+    let transactions = workload(2000);
+    let mut single = Ledger::new();
+    for transaction in &transactions {
+        let _ = single.process_transaction(transaction);
+    }
+
+    let mut first_half = Ledger::new();
+    for transaction in &transactions[..1000] {
+        let _ = first_half.process_transaction(transaction);
+    }
+    let mut sharded = ShardedLedger::new(first_half, 3, true);
+    for transaction in &transactions[1000..] {
+        sharded.process_transaction(transaction.clone())?;
+    }
+    let merged = sharded.finish()?;
+    assert_eq!(client_csv(&merged)?, client_csv(&single)?);
+    assert_eq!(
+        merged.stats.declined_withdrawals,
+        single.stats.declined_withdrawals
+    );
+    merged.check_journal()?;
+    Ok(())
+}
+
+//
+// * Split a ledger and merge it straight back, nothing is lost
+//
+#[test]
+fn test_split_and_merge_ledger() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.strict_amounts = true;
+    for transaction in &workload(500) {
+        let _ = ledger.process_transaction(transaction);
+    }
+    let expected = client_csv(&ledger)?;
+    let clients = ledger.by_client_id.len();
+    let transactions = ledger.by_transaction_id.len();
+    let declined = ledger.stats.declined_withdrawals;
+
+    let split = shard::split_ledger(ledger, 4);
+    assert_eq!(split.len(), 4);
+    for (index, shard) in split.iter().enumerate() {
+        assert!(shard.strict_amounts);
+        assert!(shard.by_client_id.keys().all(|c| *c as usize % 4 == index));
+    }
+    let merged = shard::merge_ledgers(split);
+    assert_eq!(client_csv(&merged)?, expected);
+    assert_eq!(merged.by_client_id.len(), clients);
+    assert_eq!(merged.by_transaction_id.len(), transactions);
+    assert_eq!(merged.stats.declined_withdrawals, declined);
+    assert!(merged.strict_amounts);
+    Ok(())
+}
+
+//
+// * Without keep_going a shard stops at its first rejection
+// * Its error comes back, from the next transaction sent to it or from
+//   finish
+//
+#[test]
+fn test_sharded_stops_on_error() {
+    let mut sharded = ShardedLedger::new(Ledger::new(), 2, false);
+    sharded
+        .process_transaction(create_transaction(TransactionType::Deposit, 1, 1, "10"))
+        .unwrap();
+    sharded
+        .process_transaction(create_transaction(TransactionType::Withdrawl, 1, 2, "20"))
+        .unwrap();
+    sharded
+        .process_transaction(create_transaction(TransactionType::Deposit, 2, 3, "5"))
+        .unwrap();
+    let mut result = Ok(());
+    for tx_id in 4..10000 {
+        result =
+            sharded.process_transaction(create_transaction(TransactionType::Deposit, 1, tx_id, "1"));
+        if result.is_err() {
+            break;
+        }
+    }
+    let result = result.and_then(|_| sharded.finish().map(|_| ()));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 2,
+            ..
+        })
+    ));
+}
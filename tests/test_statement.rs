@@ -0,0 +1,129 @@
+//
+// Per-client statements, from the journal
+//
+
+// # Tests:
+use payment_engine::{
+    error::PaymentError,
+    ledger::{DisputeState, Ledger, OutputFormat},
+    transaction::TransactionType,
+};
+use rust_decimal::dec;
+use std::error::Error;
+
+mod common;
+use common::create_transaction;
+
+// Client 1 deposits twice, withdraws, has one deposit disputed and resolved
+// and the other disputed and charged back. Client 2's deposit is in the
+// middle, and a withdrawal client 1 can't cover is rejected.
+fn sample_ledger() -> Ledger {
+    let mut ledger = Ledger::new();
+    let transactions = vec![
+        create_transaction(TransactionType::Deposit, 1, 1, "10"),
+        create_transaction(TransactionType::Deposit, 2, 2, "99"),
+        create_transaction(TransactionType::Deposit, 1, 3, "5.5"),
+        create_transaction(TransactionType::Withdrawl, 1, 4, "2"),
+        create_transaction(TransactionType::Withdrawl, 1, 5, "100"),
+        create_transaction(TransactionType::Dispute, 1, 1, ""),
+        create_transaction(TransactionType::Resolve, 1, 1, ""),
+        create_transaction(TransactionType::Dispute, 1, 3, ""),
+        create_transaction(TransactionType::Chargeback, 1, 3, ""),
+    ];
+    for (seq_num, mut transaction) in transactions.into_iter().enumerate() {
+        transaction.seq_num = seq_num as u32;
+        let _ = ledger.process_transaction(&transaction);
+    }
+    ledger
+}
+
+//
+// * Only the client's accepted transactions, in seq_num order
+// * Running balances after each one, the last matches the account
+// * Dispute state of the deposit/withdrawal each one is for, as of that
+//   line: a deposit charged back later still shows Processed on its own line
+//
+#[test]
+fn test_statement() -> Result<(), Box<dyn Error>> {
+    let ledger = sample_ledger();
+    let statement = ledger.statement(1)?;
+    // seq_num type available held dispute_state
+    let summary: Vec<String> = statement
+        .iter()
+        .map(|line| {
+            format!(
+                "{} {} {} {} {:?}",
+                line.seq_num.unwrap(),
+                line.tx_type,
+                line.available,
+                line.held,
+                line.dispute_state.unwrap()
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            "0 deposit 10 0 Processed",
+            "2 deposit 15.5 0 Processed",
+            "3 withdrawal 13.5 0 Processed",
+            "5 dispute 3.5 10 Disputed",
+            "6 resolve 13.5 0 Resolved",
+            "7 dispute 8.0 5.5 Disputed",
+            "8 chargeback 8.0 0.0 ChargedBack",
+        ]
+    );
+    let last = statement.last().unwrap();
+    let account = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(
+        (last.available, last.held),
+        (account.available, account.held)
+    );
+    assert_eq!(last.total, dec!(8.0));
+    assert_eq!(statement[5].tx, Some(3));
+    assert_eq!(statement[5].amount, Some(dec!(5.5)));
+    assert_eq!(statement[1].tx, Some(3));
+    assert_eq!(statement[1].dispute_state, Some(DisputeState::Processed));
+    assert_eq!(statement[6].dispute_state, Some(DisputeState::ChargedBack));
+    assert_eq!(
+        ledger.by_transaction_id.get(&3).unwrap().state,
+        DisputeState::ChargedBack
+    );
+
+    assert!(matches!(
+        ledger.statement(7),
+        Err(PaymentError::UnknownClient(7))
+    ));
+    Ok(())
+}
+
+//
+// * CSV has a header and a row per transaction
+// * JSON is an array of the same rows, amounts as strings
+//
+#[test]
+fn test_statement_output() -> Result<(), Box<dyn Error>> {
+    let ledger = sample_ledger();
+    let mut out = Vec::new();
+    ledger.dump_statement(&mut out, 2, OutputFormat::Csv)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "seq_num,type,tx,amount,available,held,total,dispute_state\n\
+         1,deposit,2,99,99,0,99,Processed\n"
+    );
+
+    let mut out = Vec::new();
+    ledger.dump_statement(&mut out, 1, OutputFormat::Json)?;
+    let value: serde_json::Value = serde_json::from_slice(&out)?;
+    let rows = value.as_array().unwrap();
+    assert_eq!(rows.len(), 7);
+    assert_eq!(rows[6]["type"], "chargeback");
+    assert_eq!(rows[6]["amount"], "5.5");
+    assert_eq!(rows[6]["total"], "8.0");
+    assert_eq!(rows[6]["dispute_state"], "ChargedBack");
+
+    assert!(ledger
+        .dump_statement(Vec::new(), 7, OutputFormat::Jsonl)
+        .is_err());
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//
+// The end of run summary, from the readers and the ledger
+//
+
+// # Tests:
+use payment_engine::{
+    ledger::Ledger,
+    shard::{merge_ledgers, split_ledger},
+    summary::{ReadSummary, RunSummary},
+    transaction::{self, Transaction},
+    wal::{self, Wal},
+};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+use tempfile::NamedTempFile;
+
+fn counts(pairs: &[(&str, u64)]) -> BTreeMap<String, u64> {
+    pairs.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+}
+
+const INPUT: &str = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,2,2,5.0\n\
+                     deposit,Frog,3,1.0\n\
+                     withdrawal,1,4,2.5\n\
+                     withdrawal,2,5,50\n\
+                     dispute,2,2,\n\
+                     chargeback,2,2,\n\
+                     deposit,2,6,1\n\
+                     dispute,1,99,\n";
+
+//
+// * The reader counts every row and the parse errors
+// * The ledger counts types, acceptances, rejections and volumes
+// * The run summary puts the rejections together
+//
+#[test]
+fn test_run_summary() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    let read = transaction::process_csv_from_buffer(
+        INPUT,
+        |transaction: Transaction| ledger.process_transaction(&transaction),
+        true,
+    )?;
+    assert_eq!(read.rows_read, 9);
+    assert_eq!(read.rejected_by_reason, counts(&[("parse_error", 1)]));
+
+    let summary = RunSummary::new(&read, &ledger);
+    assert_eq!(
+        summary.by_type,
+        counts(&[
+            ("chargeback", 1),
+            ("deposit", 3),
+            ("dispute", 2),
+            ("withdrawal", 2)
+        ])
+    );
+    assert_eq!(summary.accepted, 5);
+    assert_eq!(summary.rejected, 4);
+    assert_eq!(
+        summary.rejected_by_reason,
+        counts(&[
+            ("account_locked", 1),
+            ("insufficient_funds", 1),
+            ("parse_error", 1),
+            ("unknown_transaction", 1)
+        ])
+    );
+    assert_eq!(summary.deposited, Decimal::from_str("15.0")?);
+    assert_eq!(summary.withdrawn, Decimal::from_str("2.5")?);
+    assert_eq!(summary.charged_back, Decimal::from_str("5.0")?);
+    assert_eq!(summary.clients_created, 2);
+    assert_eq!(summary.accounts_locked, 1);
+    assert_eq!(summary.declined_withdrawals, 1);
+    assert_eq!(summary.clients, 2);
+    assert_eq!(summary.locked_clients, 1);
+    Ok(())
+}
+
+//
+// * Splitting a ledger into shards and merging it back keeps its stats
+// * Read summaries from several files add up
+//
+#[test]
+fn test_summary_merges() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    let mut read = ReadSummary::default();
+    for _ in 0..2 {
+        let file_read = transaction::process_csv_from_buffer(
+            INPUT,
+            |transaction: Transaction| ledger.process_transaction(&transaction),
+            true,
+        )?;
+        read.merge(&file_read);
+    }
+    assert_eq!(read.rows_read, 18);
+    assert_eq!(read.rejected_by_reason, counts(&[("parse_error", 2)]));
+
+    let stats = ledger.stats.clone();
+    let merged = merge_ledgers(split_ledger(ledger, 3));
+    assert_eq!(merged.stats, stats);
+    Ok(())
+}
+
+//
+// * Run INPUT with a write-ahead log, then a second run with the same log
+//   over one more deposit, recovering the first run's records from it
+// * The second run's summary only counts the second run's row
+//
+#[test]
+fn test_summary_after_wal_recovery() -> Result<(), Box<dyn Error>> {
+    let wal_file = NamedTempFile::new()?;
+    let filename = wal_file
+        .path()
+        .to_str()
+        .ok_or("Failed to get temp file path")?;
+    let mut first = Ledger::new();
+    first.attach_wal(Wal::open(filename)?);
+    transaction::process_csv_from_buffer(
+        INPUT,
+        |transaction: Transaction| first.process_transaction(&transaction),
+        true,
+    )?;
+    drop(first);
+
+    let mut second = wal::recover(Ledger::new(), filename)?;
+    second.attach_wal(Wal::open(filename)?);
+    let read = transaction::process_csv_from_buffer(
+        "type,client,tx,amount\ndeposit,1,7,100\n",
+        |transaction: Transaction| second.process_transaction(&transaction),
+        true,
+    )?;
+    let summary = RunSummary::new(&read, &second);
+    assert_eq!(summary.rows_read, 1);
+    assert_eq!(summary.by_type, counts(&[("deposit", 1)]));
+    assert_eq!((summary.accepted, summary.rejected), (1, 0));
+    assert_eq!(summary.deposited, Decimal::from_str("100")?);
+    assert_eq!(summary.declined_withdrawals, 0);
+    assert_eq!(summary.clients, 2);
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//
+// Transaction timestamps and rejecting them out of order
+//
+
+// # Tests:
+use chrono::{DateTime, Utc};
+use payment_engine::{
+    error::PaymentError,
+    ledger::Ledger,
+    shard::{merge_ledgers, split_ledger},
+    transaction::{Transaction, TransactionType},
+};
+use std::error::Error;
+use std::fs;
+use tempfile::NamedTempFile;
+
+mod common;
+use common::create_transaction;
+
+// The same transaction, timestamped `millis` after the epoch
+fn at(mut transaction: Transaction, millis: i64) -> Transaction {
+    transaction.timestamp = DateTime::from_timestamp_millis(millis);
+    transaction
+}
+
+//
+// * Client 1 deposits at 1000 and 2000, a deposit at 1500 is rejected
+// * The same time as the last one is fine, as is one without a timestamp
+// * Client 2 is ordered on their own, 500 is fine for them
+// * A rejected transaction doesn't move the client's last timestamp on
+// * Without the option, going backwards is accepted
+//
+#[test]
+fn test_reject_out_of_order() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.reject_out_of_order = true;
+    ledger.process_transaction(&at(
+        create_transaction(TransactionType::Deposit, 1, 1, "10"),
+        1000,
+    ))?;
+    ledger.process_transaction(&at(
+        create_transaction(TransactionType::Deposit, 1, 2, "10"),
+        2000,
+    ))?;
+
+    let result = ledger.process_transaction(&at(
+        create_transaction(TransactionType::Deposit, 1, 3, "10"),
+        1500,
+    ));
+    let err = result.unwrap_err();
+    assert!(matches!(
+        err,
+        PaymentError::TimestampOutOfOrder {
+            client_id: 1,
+            tx_id: 3,
+            ..
+        }
+    ));
+    assert_eq!(err.reason_code(), "timestamp_out_of_order");
+    assert!(!ledger.is_existing_transaction(3));
+
+    ledger.process_transaction(&at(
+        create_transaction(TransactionType::Dispute, 1, 1, ""),
+        2000,
+    ))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, ""))?;
+    ledger.process_transaction(&at(
+        create_transaction(TransactionType::Deposit, 2, 4, "5"),
+        500,
+    ))?;
+
+    // can't be covered, so it's rejected and 3000 isn't remembered
+    assert!(ledger
+        .process_transaction(&at(
+            create_transaction(TransactionType::Withdrawl, 1, 5, "100"),
+            3000,
+        ))
+        .is_err());
+    ledger.process_transaction(&at(
+        create_transaction(TransactionType::Withdrawl, 1, 6, "1"),
+        2500,
+    ))?;
+    assert_eq!(
+        ledger.last_timestamps.get(&1),
+        DateTime::from_timestamp_millis(2500).as_ref()
+    );
+    assert_eq!(
+        ledger.last_timestamps.get(&2),
+        DateTime::from_timestamp_millis(500).as_ref()
+    );
+
+    ledger.reject_out_of_order = false;
+    ledger.process_transaction(&at(
+        create_transaction(TransactionType::Deposit, 1, 7, "10"),
+        0,
+    ))?;
+    Ok(())
+}
+
+//
+// * Timestamps go in the dump with their transactions, in RFC 3339
+// * A loaded ledger still knows each client's last timestamp, so ordering
+//   carries on across runs
+// * So does a ledger that's been split into shards and merged back
+//
+#[test]
+fn test_timestamps_dump_and_load() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    ledger.reject_out_of_order = true;
+    ledger.process_transaction(&at(
+        create_transaction(TransactionType::Deposit, 1, 1, "10"),
+        1709294400000,
+    ))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 2, "10"))?;
+
+    let statelog = NamedTempFile::new()?;
+    let filename = statelog
+        .path()
+        .to_str()
+        .ok_or("Failed to get temp file path")?;
+    ledger.dump_ledger(filename)?;
+    let dump: serde_json::Value = serde_json::from_str(&fs::read_to_string(filename)?)?;
+    let transactions = &dump["ledger"]["by_transaction_id"];
+    assert_eq!(
+        transactions["1"]["transaction"]["timestamp"],
+        "2024-03-01T12:00:00Z"
+    );
+    assert!(transactions["2"]["transaction"]["timestamp"].is_null());
+
+    let noon: DateTime<Utc> = "2024-03-01T12:00:00Z".parse()?;
+    let loaded = Ledger::load_ledger(filename)?;
+    assert_eq!(
+        loaded
+            .by_transaction_id
+            .get(&1)
+            .unwrap()
+            .transaction
+            .timestamp,
+        Some(noon)
+    );
+    assert_eq!(loaded.last_timestamps.get(&1), Some(&noon));
+    assert!(loaded.reject_out_of_order);
+
+    let mut merged = merge_ledgers(split_ledger(loaded, 3));
+    assert_eq!(merged.last_timestamps.get(&1), Some(&noon));
+    let result = merged.process_transaction(&at(
+        create_transaction(TransactionType::Withdrawl, 1, 3, "1"),
+        1709294399999,
+    ));
+    assert!(matches!(
+        result,
+        Err(PaymentError::TimestampOutOfOrder { .. })
+    ));
+    Ok(())
+}
@@ -3,28 +3,17 @@
 //
 
 // # Tests:
+#![allow(clippy::bool_assert_comparison)]
 use payment_engine::{
-    ledger::Ledger,
-    transaction::{Transaction, TransactionType},
+    error::PaymentError,
+    ledger::{DisputePolicy, DisputeState, Ledger},
+    transaction::TransactionType,
 };
 use rust_decimal::{Decimal, dec};
 use std::error::Error;
 
-// Helper function to create a transaction
-fn create_transaction(
-    tx_type: TransactionType,
-    client_id: u16,
-    tx_id: u32,
-    amount: &str,
-) -> Transaction {
-    Transaction {
-        seq_num: 0, // Not relevant for these tests
-        tx_type,
-        client_id,
-        tx_id,
-        amount: Decimal::from_str_exact(amount).unwrap(),
-    }
-}
+mod common;
+use common::create_transaction;
 
 #[test]
 //
@@ -136,12 +125,14 @@ fn test_one_deposit_one_withdrawal_one_client() -> Result<(), Box<dyn Error>> {
 //
 // * Make a deposit for client 1 of $200 tx_id = 1
 // * Make a withdrawl for client 1 of $100 tx_id = 2
-// * Make a dispute for client 1 tx_id = 2 amount = $100
+// * Make a dispute for client 1 tx_id = 2 amount = $100, withdrawals are
+//   disputed as reversals
 // * Check to see if client 1 has (available=100, held = 100, locked=false)
 //
 #[test]
 fn test_one_deposit_one_withdrawl_one_dispute_one_client() -> Result<(), Box<dyn Error>> {
     let mut ledger = Ledger::new();
+    ledger.dispute_policy = DisputePolicy::WithdrawalsAsReversal;
 
     // Make a deposit for client 1 of $200 tx_id = 1
     let tx1 = create_transaction(TransactionType::Deposit, 1, 1, "200");
@@ -157,18 +148,20 @@ fn test_one_deposit_one_withdrawl_one_dispute_one_client() -> Result<(), Box<dyn
     let tx3 = create_transaction(TransactionType::Dispute, 1, 2, "100");
     ledger.process_transaction(&tx3)?;
 
-    // the transaction in the by_transaction_id should be a Dispute
-    // after processing the transaction.
+    // the original withdrawal should still be in by_transaction_id, now
+    // marked as disputed.
     let old_transaction = ledger.by_transaction_id.get(&tx3.tx_id).unwrap();
-    assert_eq!(old_transaction.tx_type, TransactionType::Dispute);
+    assert_eq!(old_transaction.transaction.tx_type, TransactionType::Withdrawl);
+    assert_eq!(old_transaction.state, DisputeState::Disputed);
 
-    // Check to see if client 1 has (available=0, held = 100, locked=false)
+    // Check to see if client 1 has (available=100, held = 100, locked=false)
     // After withdrawal: available = 100
-    // After dispute of tx_id 2 (amount 100): available -= 100, held += 100
+    // After dispute of tx_id 2 (amount 100): held += 100, the withdrawn
+    // funds are being claimed back
     let client1_status = ledger.by_client_id.get(&1).unwrap();
-    assert_eq!(client1_status.available, dec!(0));
+    assert_eq!(client1_status.available, dec!(100));
     assert_eq!(client1_status.held, dec!(100));
-    assert_eq!(client1_status.held + client1_status.available, dec!(100) );
+    assert_eq!(client1_status.held + client1_status.available, dec!(200) );
     assert_eq!(client1_status.locked, false);
     Ok(())
 }
@@ -330,3 +323,759 @@ fn test_deposit_deposit_chargeback_one_client() -> Result<(), Box<dyn Error>> {
     assert_eq!(client1_status.locked, true);
     Ok(())
 }
+
+//
+// * Make a deposit for client 1 of $100 tx_id = 1
+// * Dispute tx_id = 1 twice, the second dispute should be rejected
+// * Check to see if client 1 has (available=0, held = 100, locked=false)
+//
+#[test]
+fn test_double_dispute_rejected() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "100"))?;
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "100"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InvalidStateTransition {
+            tx_id: 1,
+            from: DisputeState::Disputed,
+            to: DisputeState::Disputed,
+        })
+    ));
+
+    // The second dispute shouldn't have moved any more funds
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(0));
+    assert_eq!(client1_status.held, dec!(100));
+    assert_eq!(
+        ledger.by_transaction_id.get(&1).unwrap().state,
+        DisputeState::Disputed
+    );
+    Ok(())
+}
+
+//
+// * Make a deposit for client 1 of $100 tx_id = 1
+// * Resolve tx_id = 1 without disputing it, should be rejected
+// * Check to see if client 1 has (available=100, held = 0, locked=false)
+//
+#[test]
+fn test_resolve_without_dispute_rejected() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, "100"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InvalidStateTransition {
+            tx_id: 1,
+            from: DisputeState::Processed,
+            to: DisputeState::Resolved,
+        })
+    ));
+
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(100));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(
+        ledger.by_transaction_id.get(&1).unwrap().state,
+        DisputeState::Processed
+    );
+    Ok(())
+}
+
+//
+// * Make a deposit for client 1 of $100 tx_id = 1
+// * Dispute and then resolve tx_id = 1
+// * Chargeback tx_id = 1, should be rejected as it was already resolved
+// * Check to see if client 1 has (available=100, held = 0, locked=false)
+//
+#[test]
+fn test_chargeback_after_resolve_rejected() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, "100"))?;
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 1, "100"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InvalidStateTransition {
+            tx_id: 1,
+            from: DisputeState::Resolved,
+            to: DisputeState::ChargedBack,
+        })
+    ));
+
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(100));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(client1_status.locked, false);
+    assert_eq!(
+        ledger.by_transaction_id.get(&1).unwrap().state,
+        DisputeState::Resolved
+    );
+    Ok(())
+}
+
+//
+// * Make a deposit for client 1 of $100 tx_id = 1
+// * Dispute and chargeback tx_id = 1
+// * The original deposit should still be in the ledger, charged back
+//
+#[test]
+fn test_chargeback_keeps_original_transaction() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 1, "100"))?;
+
+    let record = ledger.by_transaction_id.get(&1).unwrap();
+    assert_eq!(record.transaction.tx_type, TransactionType::Deposit);
+    assert_eq!(record.transaction.amount, Some(dec!(100)));
+    assert_eq!(record.state, DisputeState::ChargedBack);
+
+    // A second chargeback has nothing left to charge back
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 1, "100"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InvalidStateTransition { .. })
+    ));
+    Ok(())
+}
+
+//
+// * Dispute a transaction that was never seen
+// * Make a deposit for client 1 of $100 tx_id = 1, then reuse tx_id = 1
+// * Dispute tx_id = 1 with the wrong amount
+//
+#[test]
+fn test_typed_errors() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 9, "100"));
+    assert!(matches!(result, Err(PaymentError::UnknownTransaction(9))));
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "50"));
+    assert!(matches!(result, Err(PaymentError::DuplicateTransaction(1))));
+
+    ledger.strict_amounts = true;
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "50"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::AmountMismatch { tx_id: 1, .. })
+    ));
+
+    // None of the failures should have touched the balance
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(100));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    Ok(())
+}
+
+//
+// * Make a deposit for client 1 of $100 tx_id = 1, dispute and chargeback it
+// * Client 1 is now locked, deposits and withdrawals should be rejected
+// * Operator unlocks client 1, deposits and withdrawals work again
+//
+#[test]
+fn test_locked_account_rejects_until_unlocked() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 2, "50"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 1, "100"))?;
+    assert_eq!(ledger.by_client_id.get(&1).unwrap().locked, true);
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 3, "10"));
+    assert!(matches!(result, Err(PaymentError::AccountLocked(1))));
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Withdrawl, 1, 4, "10"));
+    assert!(matches!(result, Err(PaymentError::AccountLocked(1))));
+
+    // Rejected transactions aren't kept
+    assert_eq!(ledger.is_existing_transaction(3), false);
+    assert_eq!(ledger.is_existing_transaction(4), false);
+
+    ledger.process_admin_transaction(&create_transaction(TransactionType::Unlock, 1, 900, "0"))?;
+    assert_eq!(ledger.by_client_id.get(&1).unwrap().locked, false);
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 3, "10"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Withdrawl, 1, 4, "20"))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(40));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    Ok(())
+}
+
+//
+// * Operator freezes client 2 before we've seen them
+// * Deposits for client 2 are rejected until an operator unlocks them
+//
+#[test]
+fn test_freeze_account() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_admin_transaction(&create_transaction(TransactionType::Freeze, 2, 900, "0"))?;
+    assert_eq!(ledger.by_client_id.get(&2).unwrap().locked, true);
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 1, "10"));
+    assert!(matches!(result, Err(PaymentError::AccountLocked(2))));
+
+    ledger.process_admin_transaction(&create_transaction(TransactionType::Unlock, 2, 901, "0"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 1, "10"))?;
+    assert_eq!(ledger.by_client_id.get(&2).unwrap().available, dec!(10));
+    Ok(())
+}
+
+//
+// * Admin transactions on the regular feed are rejected
+// * Regular transactions on the operator input are rejected
+// * Unlocking a client we've never seen is rejected
+//
+#[test]
+fn test_admin_transactions_need_operator_input() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_admin_transaction(&create_transaction(TransactionType::Freeze, 1, 900, "0"))?;
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Unlock, 1, 901, "0"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::UnauthorizedTransaction {
+            tx_id: 901,
+            tx_type: TransactionType::Unlock,
+        })
+    ));
+    assert_eq!(ledger.by_client_id.get(&1).unwrap().locked, true);
+
+    let result =
+        ledger.process_admin_transaction(&create_transaction(TransactionType::Deposit, 1, 2, "5"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::UnauthorizedTransaction { tx_id: 2, .. })
+    ));
+
+    let result =
+        ledger.process_admin_transaction(&create_transaction(TransactionType::Unlock, 5, 902, "0"));
+    assert!(matches!(result, Err(PaymentError::UnknownClient(5))));
+    Ok(())
+}
+
+//
+// * Make a deposit for client 1 of $100 tx_id = 1
+// * Make a withdrawal for client 1 of $150 tx_id = 2, should be declined
+// * tx_id = 2 isn't kept, so it can't be disputed
+// * Check to see if client 1 has (available=100, held = 0, locked=false)
+//
+#[test]
+fn test_withdrawal_insufficient_funds() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Withdrawl, 1, 2, "150"));
+    match result {
+        Err(PaymentError::InsufficientFunds {
+            client_id,
+            tx_id,
+            available,
+            requested,
+        }) => {
+            assert_eq!(client_id, 1);
+            assert_eq!(tx_id, 2);
+            assert_eq!(available, dec!(100));
+            assert_eq!(requested, dec!(150));
+        }
+        other => panic!("Expected InsufficientFunds, got {:?}", other),
+    }
+    assert_eq!(ledger.stats.declined_withdrawals, 1);
+    assert_eq!(ledger.is_existing_transaction(2), false);
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 2, "150"));
+    assert!(matches!(result, Err(PaymentError::UnknownTransaction(2))));
+
+    // Withdrawing exactly what's available is fine
+    ledger.process_transaction(&create_transaction(TransactionType::Withdrawl, 1, 3, "100"))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, Decimal::ZERO);
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(client1_status.locked, false);
+    assert_eq!(ledger.stats.declined_withdrawals, 1);
+    Ok(())
+}
+
+//
+// Client mismatch matrix. For each of dispute, resolve and chargeback:
+//
+// * Client 1 deposits $100 tx_id = 1, client 2 deposits $50 tx_id = 2
+// * Put tx_id = 1 into the state the operation needs (disputed for
+//   resolve/chargeback)
+// * Client 2 attempts the operation against client 1's tx_id = 1
+// * It should be rejected and neither client's balances should move
+//
+#[test]
+fn test_client_mismatch_matrix() -> Result<(), Box<dyn Error>> {
+    let cases = [
+        (TransactionType::Dispute, DisputeState::Processed),
+        (TransactionType::Resolve, DisputeState::Disputed),
+        (TransactionType::Chargeback, DisputeState::Disputed),
+    ];
+
+    for (tx_type, state) in cases {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+        ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 2, "50"))?;
+        if state == DisputeState::Disputed {
+            ledger
+                .process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "100"))?;
+        }
+        let (available, held) = if state == DisputeState::Disputed {
+            (dec!(0), dec!(100))
+        } else {
+            (dec!(100), dec!(0))
+        };
+
+        let result = ledger.process_transaction(&create_transaction(tx_type.clone(), 2, 1, "100"));
+        assert!(
+            matches!(
+                result,
+                Err(PaymentError::ClientMismatch {
+                    tx_id: 1,
+                    owner: 1,
+                    client_id: 2,
+                })
+            ),
+            "{:?} by the wrong client gave {:?}",
+            tx_type,
+            result
+        );
+
+        let client1_status = ledger.by_client_id.get(&1).unwrap();
+        assert_eq!(client1_status.available, available);
+        assert_eq!(client1_status.held, held);
+        assert_eq!(client1_status.locked, false);
+
+        let client2_status = ledger.by_client_id.get(&2).unwrap();
+        assert_eq!(client2_status.available, dec!(50));
+        assert_eq!(client2_status.held, Decimal::ZERO);
+        assert_eq!(client2_status.locked, false);
+
+        assert_eq!(ledger.by_transaction_id.get(&1).unwrap().state, state);
+    }
+    Ok(())
+}
+
+//
+// * Client 2 disputes client 1's deposit, rejected
+// * Client 1 disputes it and client 2 then tries to resolve it, rejected
+// * Client 1 resolves it
+//
+#[test]
+fn test_client_mismatch_does_not_block_owner() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 2, 1, "100"));
+    assert!(matches!(result, Err(PaymentError::ClientMismatch { .. })));
+
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "100"))?;
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Resolve, 2, 1, "100"));
+    assert!(matches!(result, Err(PaymentError::ClientMismatch { .. })));
+
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, "100"))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(100));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(
+        ledger.by_transaction_id.get(&1).unwrap().state,
+        DisputeState::Resolved
+    );
+    Ok(())
+}
+
+//
+// * Make a deposit for client 1 of $400 tx_id = 1
+// * Dispute with no amount, chargeback with no amount
+// * Check to see if client 1 has (available=0, held = 0, locked=true)
+//
+#[test]
+fn test_dispute_chargeback_without_amount() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "400"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, ""))?;
+
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(0));
+    assert_eq!(client1_status.held, dec!(400));
+
+    ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 1, ""))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(0));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(client1_status.locked, true);
+    Ok(())
+}
+
+//
+// * Make a deposit for client 1 of $400 tx_id = 1
+// * Dispute with no amount, resolve with no amount
+// * Check to see if client 1 has (available=400, held = 0, locked=false)
+//
+#[test]
+fn test_dispute_resolve_without_amount() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "400"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, ""))?;
+
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(400));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(client1_status.locked, false);
+    Ok(())
+}
+
+//
+// * Without strict amounts, the amount on a dispute is how much of the
+//   original is disputed (see test_partial_disputes)
+// * With strict amounts, a dispute has to be for all of the original and a
+//   resolve/chargeback for everything disputed, a missing amount is fine
+// * A deposit without an amount is always rejected
+//
+#[test]
+fn test_strict_amounts() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "400"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "1"))?;
+    assert_eq!(ledger.by_client_id.get(&1).unwrap().held, dec!(1));
+
+    ledger.strict_amounts = true;
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, "400"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::AmountMismatch { tx_id: 1, .. })
+    ));
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, "1"))?;
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 2, "50"))?;
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 2, "5"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::AmountMismatch { tx_id: 2, .. })
+    ));
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 2, ""))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(400));
+    assert_eq!(client1_status.held, dec!(50));
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 3, ""));
+    assert!(matches!(result, Err(PaymentError::MissingAmount(3))));
+    Ok(())
+}
+
+//
+// Helper for the dispute policy tests: client 1 deposits $200 tx_id = 1 and
+// withdraws $50 tx_id = 2, then disputes the withdrawal.
+//
+fn ledger_with_disputed_withdrawal(
+    policy: DisputePolicy,
+) -> (Ledger, Result<(), PaymentError>) {
+    let mut ledger = Ledger::new();
+    ledger.dispute_policy = policy;
+    ledger
+        .process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "200"))
+        .unwrap();
+    ledger
+        .process_transaction(&create_transaction(TransactionType::Withdrawl, 1, 2, "50"))
+        .unwrap();
+    let result = ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 2, ""));
+    (ledger, result)
+}
+
+//
+// * DepositsOnly: the dispute of a withdrawal is ignored, nothing is held
+//   and the withdrawal can't then be resolved or charged back.
+// * Deposits can still be disputed.
+//
+#[test]
+fn test_dispute_policy_deposits_only() -> Result<(), Box<dyn Error>> {
+    let (mut ledger, result) = ledger_with_disputed_withdrawal(DisputePolicy::DepositsOnly);
+    result?;
+
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(150));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(
+        ledger.by_transaction_id.get(&2).unwrap().state,
+        DisputeState::Processed
+    );
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 2, ""));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InvalidStateTransition { tx_id: 2, .. })
+    ));
+
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, ""))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(-50));
+    assert_eq!(client1_status.held, dec!(200));
+    Ok(())
+}
+
+//
+// * Reject: the dispute of a withdrawal is an error, nothing is held
+//
+#[test]
+fn test_dispute_policy_reject() -> Result<(), Box<dyn Error>> {
+    let (ledger, result) = ledger_with_disputed_withdrawal(DisputePolicy::Reject);
+    assert!(matches!(
+        result,
+        Err(PaymentError::DisputeNotAllowed {
+            tx_id: 2,
+            tx_type: TransactionType::Withdrawl,
+        })
+    ));
+
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(150));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(
+        ledger.by_transaction_id.get(&2).unwrap().state,
+        DisputeState::Processed
+    );
+    Ok(())
+}
+
+//
+// * WithdrawalsAsReversal: disputing the withdrawal holds the $50 being
+//   claimed back (available=150, held=50, total=200)
+// * Resolve: the withdrawal stands (available=150, held=0, total=150)
+//
+#[test]
+fn test_dispute_policy_reversal_resolve() -> Result<(), Box<dyn Error>> {
+    let (mut ledger, result) =
+        ledger_with_disputed_withdrawal(DisputePolicy::WithdrawalsAsReversal);
+    result?;
+
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(150));
+    assert_eq!(client1_status.held, dec!(50));
+    assert_eq!(client1_status.held + client1_status.available, dec!(200));
+
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 2, ""))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(150));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(client1_status.locked, false);
+    Ok(())
+}
+
+//
+// * WithdrawalsAsReversal: disputing the withdrawal holds the $50
+// * Chargeback: the withdrawal is reversed (available=200, held=0,
+//   total=200) and the account is locked
+//
+#[test]
+fn test_dispute_policy_reversal_chargeback() -> Result<(), Box<dyn Error>> {
+    let (mut ledger, result) =
+        ledger_with_disputed_withdrawal(DisputePolicy::WithdrawalsAsReversal);
+    result?;
+
+    ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 2, ""))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(200));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(client1_status.locked, true);
+    assert_eq!(
+        ledger.by_transaction_id.get(&2).unwrap().state,
+        DisputeState::ChargedBack
+    );
+    Ok(())
+}
+
+//
+// * Client 1 deposits $100 tx_id = 1 and disputes $30 of it, then $50 more
+// * Disputing $25 more is more than the $20 left, $0 is nothing, both are
+//   rejected
+// * The chargeback takes the $80 under dispute, the other $20 stays
+// * Client 2 deposits $50 tx_id = 2, disputes $10 and then the rest
+// * With nothing left it can't be disputed again, the resolve releases all
+//   $50
+//
+#[test]
+fn test_partial_disputes() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "30"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "50"))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(20));
+    assert_eq!(client1_status.held, dec!(80));
+    let record = ledger.by_transaction_id.get(&1).unwrap();
+    assert_eq!(record.state, DisputeState::Disputed);
+    assert_eq!(record.disputed, dec!(80));
+    assert_eq!(record.undisputed(), dec!(20));
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "25"));
+    match result {
+        Err(PaymentError::InvalidDisputeAmount {
+            tx_id,
+            undisputed,
+            requested,
+        }) => {
+            assert_eq!(tx_id, 1);
+            assert_eq!(undisputed, dec!(20));
+            assert_eq!(requested, dec!(25));
+        }
+        other => panic!("Expected InvalidDisputeAmount, got {:?}", other),
+    }
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "0"));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InvalidDisputeAmount { tx_id: 1, .. })
+    ));
+
+    ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 1, ""))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(20));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(client1_status.locked, true);
+    let record = ledger.by_transaction_id.get(&1).unwrap();
+    assert_eq!(record.state, DisputeState::ChargedBack);
+    assert_eq!(
+        (record.disputed, record.resolved, record.charged_back),
+        (dec!(0), dec!(0), dec!(80))
+    );
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 2, 2, "50"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 2, 2, "10"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 2, 2, ""))?;
+    assert_eq!(ledger.by_client_id.get(&2).unwrap().held, dec!(50));
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 2, 2, ""));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InvalidStateTransition { tx_id: 2, .. })
+    ));
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 2, 2, ""))?;
+    let client2_status = ledger.by_client_id.get(&2).unwrap();
+    assert_eq!(client2_status.available, dec!(50));
+    assert_eq!(client2_status.held, Decimal::ZERO);
+    let record = ledger.by_transaction_id.get(&2).unwrap();
+    assert_eq!((record.disputed, record.resolved), (dec!(0), dec!(50)));
+    ledger.check_journal()?;
+    Ok(())
+}
+
+//
+// * Client 1 deposits $100 tx_id = 1, disputes $30 of it and resolves it
+// * The $70 never disputed can still be disputed, $20 and then the rest
+// * Charging it back takes the $70, the resolved $30 stays available
+// * With nothing left, a charged back transaction can't be disputed again
+//
+#[test]
+fn test_dispute_after_resolve() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "30"))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Resolve, 1, 1, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, "20"))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(80));
+    assert_eq!(client1_status.held, dec!(20));
+    let record = ledger.by_transaction_id.get(&1).unwrap();
+    assert_eq!(record.state, DisputeState::Disputed);
+    assert_eq!((record.disputed, record.resolved), (dec!(20), dec!(30)));
+    assert_eq!(record.undisputed(), dec!(50));
+
+    ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, ""))?;
+    ledger.process_transaction(&create_transaction(TransactionType::Chargeback, 1, 1, ""))?;
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(30));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    let record = ledger.by_transaction_id.get(&1).unwrap();
+    assert_eq!(
+        (record.disputed, record.resolved, record.charged_back),
+        (dec!(0), dec!(30), dec!(70))
+    );
+
+    let result =
+        ledger.process_transaction(&create_transaction(TransactionType::Dispute, 1, 1, ""));
+    assert!(matches!(
+        result,
+        Err(PaymentError::InvalidStateTransition { tx_id: 1, .. })
+    ));
+    ledger.check_journal()?;
+    Ok(())
+}
+
+//
+// * Make a deposit for client 1 of $100
+// * Deposits and withdrawals of $0 or a negative amount are rejected as
+//   invalid_amount, and don't touch the balances or the journal
+// * The tx_ids they used are still free
+//
+#[test]
+fn test_deposit_withdrawal_not_positive() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 1, "100"))?;
+    let entries = ledger.journal.entries().len();
+    let cases = [
+        (TransactionType::Deposit, 2, "0"),
+        (TransactionType::Deposit, 3, "-50"),
+        (TransactionType::Withdrawl, 4, "0.0"),
+        (TransactionType::Withdrawl, 5, "-50"),
+    ];
+    for (tx_type, tx_id, amount) in cases {
+        let result = ledger.process_transaction(&create_transaction(tx_type, 1, tx_id, amount));
+        let err = result.unwrap_err();
+        assert!(matches!(err, PaymentError::InvalidAmount { .. }), "{}", tx_id);
+        assert_eq!(err.reason_code(), "invalid_amount");
+        assert!(!ledger.is_existing_transaction(tx_id));
+    }
+    let client1_status = ledger.by_client_id.get(&1).unwrap();
+    assert_eq!(client1_status.available, dec!(100));
+    assert_eq!(client1_status.held, Decimal::ZERO);
+    assert_eq!(ledger.journal.entries().len(), entries);
+    assert_eq!(ledger.stats.rejected_by_reason.get("invalid_amount"), Some(&4));
+    ledger.check_journal()?;
+    Ok(())
+}
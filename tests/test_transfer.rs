@@ -0,0 +1,303 @@
+//
+// Transfers from one client to another
+//
+
+// # Tests:
+use payment_engine::{
+    error::PaymentError,
+    journal::JournalAccount,
+    ledger::{DisputeState, Ledger},
+    shard::{merge_ledgers, split_ledger, ShardedLedger},
+    transaction::{Transaction, TransactionType},
+    wal::{self, Wal},
+};
+use rust_decimal::{dec, Decimal};
+use std::error::Error;
+use tempfile::NamedTempFile;
+
+mod common;
+use common::{create_transaction, process_all};
+
+// A transfer of `amount` from client_id to destination
+fn create_transfer(client_id: u16, tx_id: u32, amount: &str, destination: u16) -> Transaction {
+    let mut transaction = create_transaction(TransactionType::Transfer, client_id, tx_id, amount);
+    transaction.destination_id = Some(destination);
+    transaction
+}
+
+// (available, held, locked) of a client
+fn balances(ledger: &Ledger, client_id: u16) -> (Decimal, Decimal, bool) {
+    let account = ledger.by_client_id.get(&client_id).unwrap();
+    (account.available, account.held, account.locked)
+}
+
+//
+// * Client 1 deposits $100 and transfers $30 to client 2, who is new
+// * Each client gets an entry of their own for the transfer, both balance
+//   and clearing is back to zero
+// * The transfer is stored for client 1, and shows on both statements
+//
+#[test]
+fn test_transfer() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "100"),
+            create_transfer(1, 2, "30", 2),
+        ],
+    );
+    assert!(results.iter().all(|r| r.is_ok()));
+    assert_eq!(balances(&ledger, 1), (dec!(70), dec!(0), false));
+    assert_eq!(balances(&ledger, 2), (dec!(30), dec!(0), false));
+    assert_eq!(ledger.stats.clients_created, 2);
+    assert_eq!(ledger.stats.transferred, dec!(30));
+
+    let entries = ledger.journal.entries();
+    assert_eq!(entries.len(), 3);
+    assert_eq!((entries[1].client_id, entries[2].client_id), (1, 2));
+    assert_eq!(entries[1].seq_num(), entries[2].seq_num());
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::Clearing),
+        Decimal::ZERO
+    );
+    ledger.check_journal()?;
+
+    let record = ledger.by_transaction_id.get(&2).unwrap();
+    assert_eq!(record.transaction.client_id, 1);
+    assert_eq!(record.state, DisputeState::Processed);
+    for client_id in [1, 2] {
+        let last = ledger.statement(client_id)?.pop().unwrap();
+        assert_eq!((last.tx_type.as_str(), last.tx), ("transfer", Some(2)));
+    }
+    Ok(())
+}
+
+//
+// * Transfers that are refused: more than client 1 has available, to
+//   themselves, without a destination, a reused tx_id, a negative amount
+//   (which would pull funds from the destination), nothing, to a locked
+//   client and from a locked client
+// * None of them move anything, or create the destination
+//
+#[test]
+fn test_transfer_rejected() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    let mut no_destination = create_transfer(1, 5, "1", 2);
+    no_destination.destination_id = None;
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "10"),
+            create_transaction(TransactionType::Deposit, 2, 2, "10"),
+            create_transfer(1, 3, "10.0001", 4),
+            create_transfer(1, 4, "1", 1),
+            no_destination,
+            create_transfer(1, 1, "1", 2),
+            create_transfer(1, 9, "-5", 2),
+            create_transfer(1, 10, "0", 2),
+            create_transaction(TransactionType::Freeze, 2, 6, ""),
+            create_transfer(1, 7, "1", 2),
+            create_transfer(2, 8, "1", 1),
+        ],
+    );
+    let reasons: Vec<&str> = results[2..]
+        .iter()
+        .filter_map(|r| r.as_ref().err().map(PaymentError::reason_code))
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![
+            "insufficient_funds",
+            "self_transfer",
+            "missing_destination",
+            "duplicate_transaction",
+            "invalid_transfer_amount",
+            "invalid_transfer_amount",
+            "account_locked",
+            "account_locked",
+        ]
+    );
+    assert!(matches!(
+        results[6],
+        Err(PaymentError::InvalidTransferAmount { tx_id: 9, .. })
+    ));
+    assert!(matches!(results[9], Err(PaymentError::AccountLocked(2))));
+    assert_eq!(balances(&ledger, 1), (dec!(10), dec!(0), false));
+    assert_eq!(balances(&ledger, 2), (dec!(10), dec!(0), true));
+    assert!(!ledger.is_existing_client(4));
+    assert_eq!(ledger.stats.transferred, Decimal::ZERO);
+    ledger.check_journal()?;
+    Ok(())
+}
+
+//
+// * Client 1 transfers $30 to client 2, who spends $25 of it
+// * Client 1 disputes it: $30 is held for client 1, client 2 gives it back
+//   and goes negative. Client 2 can't dispute it, it isn't theirs
+// * Resolving it gives client 2 the $30 back
+// * Disputing $10 of a second transfer and charging it back returns the
+//   $10 to client 1 and locks them, client 2 keeps the rest
+//
+#[test]
+fn test_transfer_disputes() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "100"),
+            create_transfer(1, 2, "30", 2),
+            create_transaction(TransactionType::Withdrawl, 2, 3, "25"),
+            create_transaction(TransactionType::Dispute, 1, 2, ""),
+            create_transaction(TransactionType::Dispute, 2, 2, ""),
+        ],
+    );
+    assert!(results[..4].iter().all(|r| r.is_ok()));
+    assert!(matches!(
+        results[4],
+        Err(PaymentError::ClientMismatch { owner: 1, .. })
+    ));
+    assert_eq!(balances(&ledger, 1), (dec!(70), dec!(30), false));
+    assert_eq!(balances(&ledger, 2), (dec!(-25), dec!(0), false));
+    ledger.check_journal()?;
+
+    process_all(
+        &mut ledger,
+        vec![create_transaction(TransactionType::Resolve, 1, 2, "")],
+    );
+    assert_eq!(balances(&ledger, 1), (dec!(70), dec!(0), false));
+    assert_eq!(balances(&ledger, 2), (dec!(5), dec!(0), false));
+
+    let results = process_all(
+        &mut ledger,
+        vec![
+            create_transfer(1, 4, "40", 2),
+            create_transaction(TransactionType::Dispute, 1, 4, "10"),
+            create_transaction(TransactionType::Chargeback, 1, 4, ""),
+        ],
+    );
+    assert!(results.iter().all(|r| r.is_ok()));
+    assert_eq!(balances(&ledger, 1), (dec!(40), dec!(0), true));
+    assert_eq!(balances(&ledger, 2), (dec!(35), dec!(0), false));
+    let record = ledger.by_transaction_id.get(&4).unwrap();
+    assert_eq!(record.state, DisputeState::ChargedBack);
+    assert_eq!(record.charged_back, dec!(10));
+    assert_eq!(ledger.stats.charged_back, dec!(10));
+    assert_eq!(
+        ledger.journal.balance(&JournalAccount::Clearing),
+        Decimal::ZERO
+    );
+    ledger.check_journal()?;
+    Ok(())
+}
+
+//
+// * Transfers and their disputes go in the write-ahead log as one record,
+//   replaying it gives the same ledger
+//
+#[test]
+fn test_transfer_wal_replay() -> Result<(), Box<dyn Error>> {
+    let wal_file = NamedTempFile::new()?;
+    let filename = wal_file
+        .path()
+        .to_str()
+        .ok_or("Failed to get temp file path")?;
+    let mut ledger = Ledger::new();
+    ledger.attach_wal(Wal::open(filename)?);
+    process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "100"),
+            create_transfer(1, 2, "30", 2),
+            create_transfer(1, 3, "300", 2),
+            create_transaction(TransactionType::Dispute, 1, 2, "12.5"),
+        ],
+    );
+    assert_eq!(wal::read_wal(filename)?.0.len(), 4);
+
+    let replayed = wal::recover(Ledger::new(), filename)?;
+    assert_eq!(replayed.by_client_id, ledger.by_client_id);
+    assert_eq!(replayed.journal.entries(), ledger.journal.entries());
+    Ok(())
+}
+
+//
+// * Split over 2 shards, client 1 can transfer to client 3 (the same
+//   shard) but not to client 2, and the ledger is left as it was
+// * A transfer from before the split between the two can't be disputed on
+//   a shard, it's fine once they're merged back
+// * Through a ShardedLedger the destination is lent to the sender's shard,
+//   so the transfer, its dispute and resolve and transfers to new clients
+//   on the other shard go through as they would single threaded
+//
+#[test]
+fn test_cross_shard_transfer() -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::new();
+    process_all(
+        &mut ledger,
+        vec![
+            create_transaction(TransactionType::Deposit, 1, 1, "100"),
+            create_transfer(1, 2, "10", 2),
+        ],
+    );
+    let mut shards = split_ledger(ledger, 2);
+    let results = process_all(
+        &mut shards[1],
+        vec![
+            create_transfer(1, 3, "5", 3),
+            create_transfer(1, 4, "5", 2),
+            create_transaction(TransactionType::Dispute, 1, 2, ""),
+        ],
+    );
+    assert!(results[0].is_ok());
+    let err = results[1].as_ref().unwrap_err();
+    assert!(matches!(
+        err,
+        PaymentError::CrossShardTransfer {
+            tx_id: 4,
+            client_id: 1,
+            destination: 2
+        }
+    ));
+    assert_eq!(err.reason_code(), "cross_shard_transfer");
+    assert!(matches!(
+        results[2],
+        Err(PaymentError::CrossShardTransfer { tx_id: 2, .. })
+    ));
+    assert_eq!(balances(&shards[1], 1), (dec!(85), dec!(0), false));
+
+    let mut merged = merge_ledgers(shards);
+    merged.check_journal()?;
+    merged.process_transaction(&create_transaction(TransactionType::Dispute, 1, 2, ""))?;
+    assert_eq!(balances(&merged, 1), (dec!(85), dec!(10), false));
+    assert_eq!(balances(&merged, 2), (dec!(0), dec!(0), false));
+
+    let transactions = vec![
+        create_transaction(TransactionType::Deposit, 1, 1, "10"),
+        create_transaction(TransactionType::Deposit, 2, 2, "1"),
+        create_transfer(1, 3, "6", 2),
+        create_transaction(TransactionType::Withdrawl, 2, 4, "7"),
+        create_transaction(TransactionType::Dispute, 1, 3, "2"),
+        create_transaction(TransactionType::Resolve, 1, 3, ""),
+        create_transfer(1, 5, "3", 4),
+        create_transfer(4, 6, "1", 3),
+    ];
+    let mut single = Ledger::new();
+    process_all(&mut single, transactions.clone());
+    let mut sharded = ShardedLedger::new(Ledger::new(), 2, true);
+    for transaction in transactions {
+        sharded.process_transaction(transaction)?;
+    }
+    let merged = sharded.finish()?;
+    assert!(merged.stats.rejected_by_reason.is_empty());
+    assert_eq!(merged.by_client_id, single.by_client_id);
+    assert_eq!(merged.stats, single.stats);
+    assert_eq!(balances(&merged, 1), (dec!(1), dec!(0), false));
+    assert_eq!(balances(&merged, 2), (dec!(0), dec!(0), false));
+    assert_eq!(balances(&merged, 3), (dec!(1), dec!(0), false));
+    assert_eq!(balances(&merged, 4), (dec!(2), dec!(0), false));
+    let record = merged.by_transaction_id.get(&3).unwrap();
+    assert_eq!((record.state, record.resolved), (DisputeState::Resolved, dec!(2)));
+    merged.check_journal()?;
+    Ok(())
+}
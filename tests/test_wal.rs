@@ -0,0 +1,272 @@
+//
+// The write-ahead log and recovering a ledger from it
+//
+
+// # Tests:
+use payment_engine::{
+    error::PaymentError,
+    ledger::Ledger,
+    transaction::{Transaction, TransactionType},
+    wal::{self, Wal, WalOutcome},
+};
+use rust_decimal::dec;
+use std::error::Error;
+use std::fs;
+use tempfile::{NamedTempFile, TempDir};
+
+mod common;
+use common::create_transaction;
+
+// A mix of accepted and rejected transactions across a few clients
+fn transactions() -> Vec<Transaction> {
+    vec![
+        create_transaction(TransactionType::Deposit, 1, 1, "100"),
+        create_transaction(TransactionType::Deposit, 2, 2, "20.5"),
+        create_transaction(TransactionType::Withdrawl, 1, 3, "30.25"),
+        create_transaction(TransactionType::Withdrawl, 2, 4, "500"), // insufficient funds
+        create_transaction(TransactionType::Deposit, 3, 5, "7"),
+        create_transaction(TransactionType::Dispute, 1, 1, ""),
+        create_transaction(TransactionType::Deposit, 1, 1, "5"), // duplicate
+        create_transaction(TransactionType::Dispute, 2, 2, ""),
+        create_transaction(TransactionType::Resolve, 1, 1, ""),
+        create_transaction(TransactionType::Chargeback, 2, 2, ""),
+        create_transaction(TransactionType::Deposit, 2, 6, "1"), // locked
+        create_transaction(TransactionType::Deposit, 3, 7, "0.0001"),
+        create_transaction(TransactionType::Resolve, 3, 5, ""), // not disputed
+        create_transaction(TransactionType::Withdrawl, 3, 8, "7.0001"),
+    ]
+}
+
+// Process the transactions into a fresh ledger, rejections and all
+fn apply(ledger: &mut Ledger, transactions: &[Transaction]) {
+    for transaction in transactions {
+        let _ = ledger.process_transaction(transaction);
+    }
+}
+
+// Run all the transactions with a write-ahead log, returning its contents
+fn logged_run(dir: &TempDir) -> Result<Vec<u8>, Box<dyn Error>> {
+    let filename = dir.path().join("full.wal");
+    let filename = filename.to_str().ok_or("Failed to get temp file path")?;
+    let mut ledger = Ledger::new();
+    ledger.attach_wal(Wal::open(filename)?);
+    apply(&mut ledger, &transactions());
+    Ok(fs::read(filename)?)
+}
+
+// Simple LCG so the "random" offsets are the same every run
+fn next_offset(seed: &mut u64, len: usize) -> usize {
+    *seed = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    ((*seed >> 33) as usize) % (len + 1)
+}
+
+//
+// * Every transaction, accepted or not, gets a record with an increasing lsn
+// * Accepted records carry the account afterwards
+//
+#[test]
+fn test_wal_records_every_transaction() -> Result<(), Box<dyn Error>> {
+    let dir = TempDir::new()?;
+    let contents = logged_run(&dir)?;
+    let log = NamedTempFile::new()?;
+    fs::write(log.path(), &contents)?;
+
+    let (records, valid_len) = wal::read_wal(log.path().to_str().unwrap())?;
+    assert_eq!(records.len(), transactions().len());
+    assert_eq!(valid_len, contents.len() as u64);
+    for (i, record) in records.iter().enumerate() {
+        assert_eq!(record.lsn, i as u64 + 1);
+        assert!(!record.admin);
+    }
+    match &records[2].outcome {
+        WalOutcome::Accepted { account, .. } => assert_eq!(account.available, dec!(69.75)),
+        other => panic!("expected an accepted record, got {:?}", other),
+    }
+    assert!(matches!(records[3].outcome, WalOutcome::Rejected { .. }));
+    Ok(())
+}
+
+//
+// * Build a full log, then cut it off at the end of every record and at
+//   a few hundred pseudo-random offsets
+// * Recovering from each cut log gives the same ledger as running just
+//   the transactions whose records survived
+//
+#[test]
+fn test_recover_from_truncated_wal() -> Result<(), Box<dyn Error>> {
+    let dir = TempDir::new()?;
+    let contents = logged_run(&dir)?;
+
+    let mut offsets: Vec<usize> = contents
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| **b == b'\n')
+        .map(|(i, _)| i + 1)
+        .collect();
+    offsets.push(0);
+    let mut seed = 42;
+    for _ in 0..300 {
+        offsets.push(next_offset(&mut seed, contents.len()));
+    }
+
+    let all = transactions();
+    let torn = dir.path().join("torn.wal");
+    let torn = torn.to_str().ok_or("Failed to get temp file path")?;
+    for offset in offsets {
+        fs::write(torn, &contents[..offset])?;
+        let kept = contents[..offset].iter().filter(|b| **b == b'\n').count();
+
+        let recovered = wal::recover(Ledger::new(), torn)?;
+        let mut expected = Ledger::new();
+        apply(&mut expected, &all[..kept]);
+
+        assert_eq!(recovered.last_lsn, kept as u64, "offset {}", offset);
+        assert_eq!(recovered.by_client_id, expected.by_client_id, "offset {}", offset);
+        assert_eq!(
+            recovered.by_transaction_id.len(),
+            expected.by_transaction_id.len(),
+            "offset {}",
+            offset
+        );
+    }
+    Ok(())
+}
+
+//
+// * Cut the log part way through a record and reopen it
+// * The torn record is dropped and new records carry on from the last
+//   good lsn, so the whole log recovers cleanly
+//
+#[test]
+fn test_reopen_torn_wal() -> Result<(), Box<dyn Error>> {
+    let dir = TempDir::new()?;
+    let contents = logged_run(&dir)?;
+    let all = transactions();
+
+    // Halfway through the 6th record
+    let ends: Vec<usize> = contents
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| **b == b'\n')
+        .map(|(i, _)| i + 1)
+        .collect();
+    let cut = (ends[4] + ends[5]) / 2;
+    let torn = dir.path().join("torn.wal");
+    let torn = torn.to_str().ok_or("Failed to get temp file path")?;
+    fs::write(torn, &contents[..cut])?;
+
+    let mut ledger = wal::recover(Ledger::new(), torn)?;
+    assert_eq!(ledger.last_lsn, 5);
+    ledger.attach_wal(Wal::open(torn)?);
+    assert_eq!(fs::metadata(torn)?.len(), ends[4] as u64);
+    apply(&mut ledger, &all[5..]);
+    assert_eq!(ledger.last_lsn, all.len() as u64);
+
+    let recovered = wal::recover(Ledger::new(), torn)?;
+    let mut expected = Ledger::new();
+    apply(&mut expected, &all);
+    assert_eq!(recovered.by_client_id, expected.by_client_id);
+    assert_eq!(recovered.by_client_id, ledger.by_client_id);
+    Ok(())
+}
+
+//
+// * Flip a byte in the middle of the log
+// * Only the records before the damaged one are recovered
+//
+#[test]
+fn test_recover_stops_at_corrupt_record() -> Result<(), Box<dyn Error>> {
+    let dir = TempDir::new()?;
+    let mut contents = logged_run(&dir)?;
+    let all = transactions();
+
+    let ends: Vec<usize> = contents
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| **b == b'\n')
+        .map(|(i, _)| i + 1)
+        .collect();
+    // somewhere in the json of the 4th record
+    let pos = ends[2] + 20;
+    contents[pos] = if contents[pos] == b'0' { b'1' } else { b'0' };
+    let log = dir.path().join("corrupt.wal");
+    let log = log.to_str().ok_or("Failed to get temp file path")?;
+    fs::write(log, &contents)?;
+
+    let recovered = wal::recover(Ledger::new(), log)?;
+    let mut expected = Ledger::new();
+    apply(&mut expected, &all[..3]);
+    assert_eq!(recovered.last_lsn, 3);
+    assert_eq!(recovered.by_client_id, expected.by_client_id);
+    Ok(())
+}
+
+//
+// * Run half the transactions with a log and take a snapshot
+// * Run the rest, then rebuild from the snapshot plus the log
+// * Records already in the snapshot aren't applied twice
+// * The declined withdrawal is in the snapshot, and like the other run
+//   counts that isn't carried over
+//
+#[test]
+fn test_recover_from_snapshot_and_wal_tail() -> Result<(), Box<dyn Error>> {
+    let dir = TempDir::new()?;
+    let all = transactions();
+    let log = dir.path().join("run.wal");
+    let log = log.to_str().ok_or("Failed to get temp file path")?;
+    let snapshot = dir.path().join("snapshot.json");
+    let snapshot = snapshot.to_str().ok_or("Failed to get temp file path")?;
+
+    let mut ledger = Ledger::new();
+    ledger.attach_wal(Wal::open(log)?);
+    apply(&mut ledger, &all[..7]);
+    ledger.dump_ledger(snapshot)?;
+    apply(&mut ledger, &all[7..]);
+
+    let loaded = Ledger::load_ledger(snapshot)?;
+    assert_eq!(loaded.last_lsn, 7);
+    let recovered = wal::recover(loaded, log)?;
+    assert_eq!(recovered.last_lsn, all.len() as u64);
+    assert_eq!(recovered.by_client_id, ledger.by_client_id);
+    assert_eq!(
+        recovered.by_transaction_id.len(),
+        ledger.by_transaction_id.len()
+    );
+    assert_eq!(ledger.stats.declined_withdrawals, 1);
+    assert_eq!(recovered.stats.declined_withdrawals, 0);
+    Ok(())
+}
+
+//
+// * Replaying into a ledger that already has tx_id = 1 rejects the logged
+//   deposit as a duplicate, which isn't what the log says happened
+// * Replaying into one where client 1 already has $10 accepts it, but
+//   leaves client 1 with a different balance than the log has
+//
+#[test]
+fn test_recover_replay_mismatch() -> Result<(), Box<dyn Error>> {
+    let dir = TempDir::new()?;
+    let contents = logged_run(&dir)?;
+    let log = NamedTempFile::new()?;
+    fs::write(log.path(), &contents)?;
+    let log = log.path().to_str().ok_or("Failed to get temp file path")?;
+
+    let mut ledger = Ledger::new();
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 9, 1, "10"))?;
+    let result = wal::recover(ledger, log);
+    assert!(matches!(
+        result,
+        Err(PaymentError::WalOutcomeMismatch {
+            lsn: 1,
+            accepted: false
+        })
+    ));
+
+    let mut ledger = Ledger::new();
+    ledger.process_transaction(&create_transaction(TransactionType::Deposit, 1, 99, "10"))?;
+    let result = wal::recover(ledger, log);
+    assert!(matches!(result, Err(PaymentError::WalBalanceMismatch(1))));
+    Ok(())
+}